@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE recovery_codes
+  (
+     id         VARCHAR(255) PRIMARY KEY,
+     user_id    VARCHAR(255) NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+     code_hash  VARCHAR(255) NOT NULL,
+     used_at    TIMESTAMP,
+     created_at TIMESTAMP NOT NULL
+  );
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE refresh_tokens
+  (
+     id         VARCHAR(255) PRIMARY KEY,
+     user_id    VARCHAR(255) NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+     family_id  VARCHAR(255) NOT NULL,
+     token_hash VARCHAR(255) NOT NULL UNIQUE,
+     expires_at TIMESTAMP NOT NULL,
+     revoked_at TIMESTAMP,
+     created_at TIMESTAMP NOT NULL
+  );
+
+CREATE INDEX refresh_tokens_family_id_idx ON refresh_tokens (family_id);
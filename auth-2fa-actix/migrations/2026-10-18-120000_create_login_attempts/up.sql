@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE login_attempts
+  (
+     attempt_key     VARCHAR(255) PRIMARY KEY,
+     failures        INTEGER NOT NULL,
+     locked_until    TIMESTAMP,
+     last_failure_at TIMESTAMP NOT NULL
+  )
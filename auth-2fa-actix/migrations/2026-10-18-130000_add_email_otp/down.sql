@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE email_otps;
+ALTER TABLE users DROP COLUMN default_factor;
+ALTER TABLE users DROP COLUMN email_otp_enabled;
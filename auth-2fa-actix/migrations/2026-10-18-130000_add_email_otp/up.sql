@@ -0,0 +1,11 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN email_otp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN default_factor VARCHAR(16) NOT NULL DEFAULT 'totp';
+
+CREATE TABLE email_otps
+  (
+     user_id    VARCHAR(255) PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+     code_hash  VARCHAR(255) NOT NULL,
+     expires_at TIMESTAMP NOT NULL,
+     created_at TIMESTAMP NOT NULL
+  );
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE password_resets;
+ALTER TABLE users DROP COLUMN sessions_valid_after;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN sessions_valid_after TIMESTAMP;
+
+CREATE TABLE password_resets
+  (
+     id         VARCHAR(255) PRIMARY KEY,
+     user_id    VARCHAR(255) NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+     token_hash VARCHAR(255) NOT NULL UNIQUE,
+     expires_at TIMESTAMP NOT NULL,
+     used_at    TIMESTAMP,
+     created_at TIMESTAMP NOT NULL
+  );
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE sessions
+  (
+     id           VARCHAR(255) PRIMARY KEY,
+     user_id      VARCHAR(255) NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+     user_agent   TEXT,
+     ip           VARCHAR(64),
+     created_at   TIMESTAMP NOT NULL,
+     last_seen_at TIMESTAMP NOT NULL,
+     revoked_at   TIMESTAMP
+  );
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
@@ -0,0 +1,26 @@
+-- Your SQL goes here
+CREATE TABLE audit_events
+  (
+     id         VARCHAR(255) PRIMARY KEY,
+     -- null for attempts on an unknown account
+     user_id    VARCHAR(255) REFERENCES users(id) ON DELETE CASCADE,
+     action     VARCHAR(32) NOT NULL,
+     outcome    VARCHAR(16) NOT NULL,
+     ip         VARCHAR(64),
+     user_agent TEXT,
+     detail     TEXT,
+     created_at TIMESTAMP NOT NULL
+  );
+
+CREATE INDEX audit_events_user_id_created_at_idx ON audit_events (user_id, created_at);
+
+-- append-only, rows only go away with the user they belong to
+CREATE FUNCTION audit_events_reject_update() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+  BEFORE UPDATE ON audit_events
+  FOR EACH ROW EXECUTE FUNCTION audit_events_reject_update();
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- accounts from before verification existed keep working
+UPDATE users SET email_verified = TRUE;
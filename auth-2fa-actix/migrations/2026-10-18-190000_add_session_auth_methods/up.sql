@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE sessions ADD COLUMN amr VARCHAR(64) NOT NULL DEFAULT 'pwd';
+ALTER TABLE sessions ADD COLUMN auth_time TIMESTAMP;
+UPDATE sessions SET auth_time = created_at;
+ALTER TABLE sessions ALTER COLUMN auth_time SET NOT NULL;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users DROP COLUMN otp_period;
+ALTER TABLE users DROP COLUMN otp_digits;
+ALTER TABLE users DROP COLUMN otp_algorithm;
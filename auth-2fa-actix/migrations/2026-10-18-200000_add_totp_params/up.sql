@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- left unset for existing secrets, those use the legacy SHA1/6/30
+ALTER TABLE users ADD COLUMN otp_algorithm VARCHAR(8);
+ALTER TABLE users ADD COLUMN otp_digits INTEGER;
+ALTER TABLE users ADD COLUMN otp_period INTEGER;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- register and email changes check for the address first, this catches the
+-- ones racing past that check. fails if duplicates already exist, those have
+-- to be merged by hand first
+CREATE UNIQUE INDEX users_email_lower_idx ON users (lower(email));
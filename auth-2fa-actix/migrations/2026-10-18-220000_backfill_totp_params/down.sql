@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- nothing to undo, rows with SHA1/6/30 behave the same as unset ones
+SELECT 1;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- secrets from before the params were stored were all made with the old
+-- fixed ones, spelled out so they don't depend on the current settings
+UPDATE users
+SET otp_algorithm = 'SHA1', otp_digits = 6, otp_period = 30
+WHERE otp_base32 IS NOT NULL AND otp_algorithm IS NULL;
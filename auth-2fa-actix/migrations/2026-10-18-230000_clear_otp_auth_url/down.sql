@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- the urls can't be brought back, nothing reads them anyway
+SELECT 1;
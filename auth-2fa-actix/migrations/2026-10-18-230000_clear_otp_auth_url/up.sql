@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- rows enrolled before secrets were encrypted still have the url with the
+-- plaintext secret in it, it is rebuilt from the secret when needed
+UPDATE users SET otp_auth_url = NULL WHERE otp_auth_url IS NOT NULL;
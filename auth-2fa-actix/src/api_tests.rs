@@ -0,0 +1,1749 @@
+// end to end tests, every request goes through the real routes and
+// middleware with the in-memory store and a clock the test controls
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use actix_http::Request;
+use actix_web::{
+    dev::{Service, ServiceResponse},
+    http::{header, StatusCode},
+    test::{self, TestRequest},
+    web::Data,
+    App,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use jwt::SignWithKey;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::{
+    crypto::{keyed_hash, SecretCipher},
+    email_verification::verification_token,
+    jwt_keys::JwtKeys,
+    lockout::{account_key, LOGIN_SCOPE},
+    mailer::{InMemoryMailer, Mailer},
+    middleware::auth_middleware::TokenClaims,
+    model::{EmailOtp, ADMIN_ROLE},
+    otp::{build_totp, Clock, TotpParams},
+    password::{HashPool, PasswordScheme},
+    password_policy::PasswordPolicy,
+    routes,
+    settings::Settings,
+    store::{memory::InMemoryStore, UserStore},
+};
+
+// start of a totp step, so `advance` lands exactly on the next ones
+const START: u64 = 1_700_000_010;
+const PASSWORD: &str = "correct horse battery staple";
+const TOTP_STEP_SECS: u64 = 30;
+const PROXY: &str = "10.0.0.1";
+
+fn settings() -> Settings {
+    let mut settings = Settings {
+        jwt_keys: "1:testdata/jwt-test-1.pem,2:testdata/jwt-test-2.pem".to_string(),
+        hash_secret: "api-test".to_string(),
+        trusted_proxies: vec![PROXY.parse().unwrap()],
+        ..Settings::default()
+    };
+    settings.password_policy.breached_passwords_file =
+        "testdata/breached-passwords.txt".to_string();
+    settings
+}
+
+fn password_scheme(settings: &Settings) -> PasswordScheme {
+    PasswordScheme::new(&settings.hash_secret, &settings.password_hash).unwrap()
+}
+
+struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    fn advance_steps(&self, steps: u64) {
+        self.0.fetch_add(steps * TOTP_STEP_SECS, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+async fn init_app(
+    clock: Arc<ManualClock>,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    init_app_with_store(clock, Arc::new(InMemoryStore::default())).await
+}
+
+// for tests that need to reach into the store, e.g. to make an admin
+async fn init_app_with_store(
+    clock: Arc<ManualClock>,
+    store: Arc<InMemoryStore>,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    init_app_with_mailer(clock, store, Arc::new(InMemoryMailer::default())).await
+}
+
+// for tests that read the mails that were sent
+async fn init_app_with_mailer(
+    clock: Arc<ManualClock>,
+    store: Arc<InMemoryStore>,
+    mailer: Arc<InMemoryMailer>,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    let store: Data<dyn UserStore> = Data::from(store as Arc<dyn UserStore>);
+    let mailer: Data<dyn Mailer> = Data::from(mailer as Arc<dyn Mailer>);
+    let clock: Data<dyn Clock> = Data::from(clock as Arc<dyn Clock>);
+    let cipher = SecretCipher::from_keys(&format!("1:{}", "00".repeat(32))).unwrap();
+
+    test::init_service(
+        App::new()
+            .app_data(store)
+            .app_data(Data::new(cipher))
+            .app_data(Data::new(
+                JwtKeys::from_keys(&settings().jwt_keys, 0).unwrap(),
+            ))
+            .app_data(Data::new(HashPool::new(2, password_scheme(&settings()))))
+            .app_data(Data::new(
+                PasswordPolicy::new(&settings().password_policy).unwrap(),
+            ))
+            .app_data(mailer)
+            .app_data(clock)
+            .app_data(Data::new(settings()))
+            .configure(routes),
+    )
+    .await
+}
+
+fn new_clock() -> Arc<ManualClock> {
+    Arc::new(ManualClock(AtomicU64::new(START)))
+}
+
+async fn call<S>(app: &S, req: TestRequest) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let resp = test::call_service(app, req.to_request()).await;
+    let status = resp.status();
+    // the auth middleware rejects with an empty body
+    let body = test::read_body(resp).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn post<S>(app: &S, uri: &str, token: Option<&str>, body: Value) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let mut req = TestRequest::post().uri(uri).set_json(body);
+    if let Some(token) = token {
+        req = req.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+    }
+    call(app, req).await
+}
+
+async fn get<S>(app: &S, uri: &str, token: &str) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let req = TestRequest::get()
+        .uri(uri)
+        .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+    call(app, req).await
+}
+
+// registers and verifies the address with a link like the one in the mail
+async fn register<S>(app: &S, email: &str) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let registered = register_unverified(app, email).await;
+    if registered.0 == StatusCode::OK {
+        let token = verification_token(&settings(), email, Utc::now().timestamp() + 60);
+        let (status, _) = post(app, "/auth/email/verify", None, json!({"token": token})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    registered
+}
+
+async fn register_unverified<S>(app: &S, email: &str) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    post(
+        app,
+        "/auth/register",
+        None,
+        json!({"name": "test", "email": email, "password": PASSWORD}),
+    )
+    .await
+}
+
+async fn login<S>(app: &S, email: &str, password: &str) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    post(
+        app,
+        "/auth/login",
+        None,
+        json!({"email": email, "password": password}),
+    )
+    .await
+}
+
+fn code_at(secret: &str, time: u64) -> String {
+    let totp = &settings().totp;
+    code_with(&TotpParams::defaults(totp), secret, time)
+}
+
+fn code_with(params: &TotpParams, secret: &str, time: u64) -> String {
+    build_totp(&settings().totp, params, secret)
+        .unwrap()
+        .generate(time)
+}
+
+// registers and enrolls totp, returns the session token, the secret and the
+// recovery codes
+async fn enroll<S>(app: &S, clock: &ManualClock, email: &str) -> (String, String, Vec<String>)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    register(app, email).await;
+    let (_, body) = login(app, email, PASSWORD).await;
+    let token = body["jwt_token"].as_str().unwrap().to_owned();
+
+    let (status, body) = post(app, "/auth/otp/generate", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let secret = body["secret"].as_str().unwrap().to_owned();
+
+    let code = code_at(&secret, clock.now());
+    let (status, body) = post(
+        app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let recovery_codes = body["recovery_codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c.as_str().unwrap().to_owned())
+        .collect();
+    (token, secret, recovery_codes)
+}
+
+async fn pre_auth_token<S>(app: &S, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let (status, body) = login(app, email, PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["2FA_enabled"], true);
+    assert!(body.get("jwt_token").is_none());
+    body["pre_auth_token"].as_str().unwrap().to_owned()
+}
+
+#[actix_web::test]
+async fn register_rejects_a_taken_email() {
+    let app = init_app(new_clock()).await;
+
+    let (status, body) = register(&app, "taken@example.com").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "success");
+
+    let (status, body) = register(&app, "taken@example.com").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["status"], "fail");
+    // addresses are unique regardless of case
+    let (status, _) = register(&app, "Taken@Example.com").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // and work whatever case they are typed in
+    let (status, _) = register(&app, "Mixed@Example.com").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "mixed@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn login_gives_the_same_answer_for_unknown_users_and_wrong_passwords() {
+    let app = init_app(new_clock()).await;
+    register(&app, "login@example.com").await;
+
+    let (status, wrong_password) = login(&app, "login@example.com", "nope").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, unknown_user) = login(&app, "nobody@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(wrong_password, unknown_user);
+
+    let (status, body) = login(&app, "login@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["2FA_enabled"], false);
+    assert!(body["jwt_token"].is_string());
+    assert!(body["refresh_token"].is_string());
+
+    // unknown emails lock out after as many failures as real accounts
+    for email in ["login@example.com", "stranger@example.com"] {
+        for _ in 0..6 {
+            let (status, _) = login(&app, email, "nope").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) = login(&app, email, "nope").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+#[actix_web::test]
+async fn repeated_wrong_passwords_lock_the_account_for_a_while() {
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_store(new_clock(), store.clone()).await;
+    register(&app, "lockout@example.com").await;
+
+    // five free failures, the sixth locks
+    for _ in 0..6 {
+        let (status, _) = login(&app, "lockout@example.com", "nope").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let req = TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({"email": "lockout@example.com", "password": PASSWORD}));
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: i64 = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=30).contains(&retry_after));
+
+    // once the lockout has run out (moved into the past here) the right
+    // password works again and clears the count
+    let user = store.get_user_by_email("lockout@example.com").unwrap();
+    store
+        .record_failed_attempt(
+            &account_key(LOGIN_SCOPE, &user.id),
+            NaiveDateTime::MIN,
+            &|_| Some(Duration::seconds(-1)),
+        )
+        .unwrap();
+    let (status, _) = login(&app, "lockout@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "lockout@example.com", "nope").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "lockout@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn the_ip_lockout_sees_clients_behind_a_trusted_proxy() {
+    let app = init_app(new_clock()).await;
+    let attempt = |n: usize, peer: &str, forwarded: &str| {
+        TestRequest::post()
+            .uri("/auth/login")
+            .peer_addr(format!("{peer}:4000").parse().unwrap())
+            .insert_header(("x-forwarded-for", forwarded.to_owned()))
+            .set_json(json!({"email": format!("proxied{n}@example.com"), "password": "nope"}))
+    };
+
+    // a new email every time so only the ip counter adds up, twenty free
+    // failures and the next one locks
+    for n in 0..21 {
+        let (status, _) = call(&app, attempt(n, PROXY, "203.0.113.5")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _) = call(&app, attempt(21, PROXY, "203.0.113.5")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    // the client can't get out of it by putting something in front
+    let (status, _) = call(&app, attempt(22, PROXY, "198.51.100.1, 203.0.113.5")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // other clients behind the same proxy aren't locked out with it
+    let (status, _) = call(&app, attempt(23, PROXY, "203.0.113.6")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // and the header is ignored from anyone but the proxy
+    let (status, _) = call(&app, attempt(24, "192.0.2.9", "203.0.113.5")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn otp_routes_need_a_session_token() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (status, _) = post(&app, "/auth/otp/generate", None, json!({})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // a pre-auth token only gets through to validate
+    enroll(&app, &clock, "scope@example.com").await;
+    let pre_auth = pre_auth_token(&app, "scope@example.com").await;
+    let (status, _) = post(&app, "/auth/otp/generate", Some(&pre_auth), json!({})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn verify_rejects_a_wrong_code() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    register(&app, "verify+otp@example.com").await;
+    let (_, body) = login(&app, "verify+otp@example.com", PASSWORD).await;
+    let token = body["jwt_token"].as_str().unwrap().to_owned();
+
+    let (_, body) = post(&app, "/auth/otp/generate", Some(&token), json!({})).await;
+    let secret = body["secret"].as_str().unwrap();
+    // the '+' would read as a space if it weren't encoded
+    assert!(body["otp_auth_url"]
+        .as_str()
+        .unwrap()
+        .starts_with("otpauth://totp/anusikh:verify%2Botp@example.com?secret="));
+
+    // a code from long ago is as good as a wrong one
+    let stale = code_at(secret, clock.now() - 10 * TOTP_STEP_SECS);
+    let (status, _) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": stale}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // guesses count towards the same lockout as /auth/otp/validate
+    for _ in 0..5 {
+        let (status, _) = post(
+            &app,
+            "/auth/otp/verify",
+            Some(&token),
+            json!({"token": "wrong"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+    let code = code_at(secret, clock.now());
+    let (status, _) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    let (_, body) = login(&app, "verify+otp@example.com", PASSWORD).await;
+    assert_eq!(body["2FA_enabled"], false);
+}
+
+#[actix_web::test]
+async fn qr_code_is_only_served_until_the_secret_is_verified() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    register(&app, "qr@example.com").await;
+    let token = session_token(&app, "qr@example.com").await;
+    let qr = |format: &'static str| {
+        let req = TestRequest::get()
+            .uri(&format!("/auth/otp/qr?format={format}"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+        test::call_service(&app, req.to_request())
+    };
+
+    let (_, body) = post(&app, "/auth/otp/generate", Some(&token), json!({})).await;
+    let secret = body["secret"].as_str().unwrap().to_owned();
+    let resp = qr("png").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/png"
+    );
+    assert_eq!(
+        resp.headers().get(header::CACHE_CONTROL).unwrap(),
+        "no-store"
+    );
+    let png = test::read_body(resp).await;
+    assert!(png.starts_with(b"\x89PNG"));
+    let resp = qr("svg").await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/svg+xml"
+    );
+
+    let code = code_at(&secret, clock.now());
+    let (status, _) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(qr("png").await.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn totp_login_validate_and_disable() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (token, secret, recovery_codes) = enroll(&app, &clock, "flow@example.com").await;
+    assert_eq!(recovery_codes.len(), 10);
+    // a new secret would silently turn 2FA off
+    let (status, _) = post(&app, "/auth/otp/generate", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    // and verifying again would hand out new recovery codes
+    let code = code_at(&secret, clock.now());
+    let (status, _) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    clock.advance_steps(1);
+    let pre_auth = pre_auth_token(&app, "flow@example.com").await;
+    let code = code_at(&secret, clock.now());
+    let (status, body) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session = body["jwt_token"].as_str().unwrap().to_owned();
+
+    let (status, body) = post(&app, "/auth/otp/disable", Some(&session), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["otp_disabled"], true);
+    assert_eq!(body["user"]["otp_enabled"], false);
+
+    let (_, body) = login(&app, "flow@example.com", PASSWORD).await;
+    assert_eq!(body["2FA_enabled"], false);
+    assert!(body["jwt_token"].is_string());
+}
+
+#[actix_web::test]
+async fn validate_rejects_replayed_and_stale_codes() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (_, secret, _) = enroll(&app, &clock, "replay@example.com").await;
+    let pre_auth = pre_auth_token(&app, "replay@example.com").await;
+
+    // the code used to verify can't be used again
+    let used = code_at(&secret, clock.now());
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": used}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // outside the skew window
+    clock.advance_steps(3);
+    let stale = code_at(&secret, clock.now() - 2 * TOTP_STEP_SECS);
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": stale}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let current = code_at(&secret, clock.now());
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": current}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let pre_auth = pre_auth_token(&app, "replay@example.com").await;
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": current}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn validate_allows_one_step_of_clock_drift() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (_, secret, _) = enroll(&app, &clock, "skew@example.com").await;
+    clock.advance_steps(5);
+
+    let pre_auth = pre_auth_token(&app, "skew@example.com").await;
+    let too_far = code_at(&secret, clock.now() + 2 * TOTP_STEP_SECS);
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": too_far}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let ahead = code_at(&secret, clock.now() + TOTP_STEP_SECS);
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": ahead}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+// the code in the last mail sent
+fn mailed_code(mailer: &InMemoryMailer) -> String {
+    let body = mailer.sent().last().unwrap().body.clone();
+    body.split_whitespace()
+        .find(|word| word.len() == 7 && word.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap()
+        .trim_end_matches('.')
+        .to_owned()
+}
+
+#[actix_web::test]
+async fn email_codes_as_the_second_factor() {
+    let store = Arc::new(InMemoryStore::default());
+    let mailer = Arc::new(InMemoryMailer::default());
+    let app = init_app_with_mailer(new_clock(), store.clone(), mailer.clone()).await;
+    register(&app, "mailed@example.com").await;
+    let token = session_token(&app, "mailed@example.com").await;
+
+    let (status, _) = post(&app, "/auth/otp/email/send", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let code = mailed_code(&mailer);
+    let (status, body) = post(
+        &app,
+        "/auth/otp/email/enable",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user"]["default_factor"], "email");
+
+    // login mails a code and only hands out a pre-auth token
+    let (_, body) = login(&app, "mailed@example.com", PASSWORD).await;
+    assert_eq!(body["second_factor"], "email");
+    let pre_auth = body["pre_auth_token"].as_str().unwrap().to_owned();
+    let code = mailed_code(&mailer);
+    let (status, body) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"email_code": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session = body["jwt_token"].as_str().unwrap().to_owned();
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"email_code": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // a code past its expiry is refused
+    let pre_auth = pre_auth_token(&app, "mailed@example.com").await;
+    let user = store.get_user_by_email("mailed@example.com").unwrap();
+    let now = Utc::now().naive_utc();
+    store
+        .set_email_otp(EmailOtp {
+            user_id: user.id,
+            code_hash: keyed_hash(&settings().hash_secret, "123456"),
+            expires_at: now - Duration::seconds(1),
+            created_at: now - Duration::minutes(10),
+        })
+        .unwrap();
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"email_code": "123456"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) = post(&app, "/auth/otp/email/disable", Some(&session), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = login(&app, "mailed@example.com", PASSWORD).await;
+    assert_eq!(body["2FA_enabled"], false);
+
+    let (_, body) = get(&app, "/auth/audit-log?limit=100", &session).await;
+    let actions: Vec<_> = body["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert!(actions.contains(&"email_otp_enable"));
+    assert!(actions.contains(&"email_otp_disable"));
+}
+
+#[actix_web::test]
+async fn enabling_email_codes_locks_out_guessing() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_mailer(new_clock(), store, mailer.clone()).await;
+    register(&app, "guessed@example.com").await;
+    let token = session_token(&app, "guessed@example.com").await;
+
+    let (status, _) = post(&app, "/auth/otp/email/send", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let code = mailed_code(&mailer);
+    let wrong = if code == "000000" { "111111" } else { "000000" };
+
+    // five free failures, the sixth locks, after that not even the right
+    // code gets through
+    for _ in 0..6 {
+        let (status, _) = post(
+            &app,
+            "/auth/otp/email/enable",
+            Some(&token),
+            json!({"token": wrong}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+    let (status, _) = post(
+        &app,
+        "/auth/otp/email/enable",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn email_codes_are_only_sent_a_few_times_in_a_row() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_mailer(new_clock(), store, mailer.clone()).await;
+    register(&app, "flooded@example.com").await;
+    let token = session_token(&app, "flooded@example.com").await;
+    let sent_before = mailer.sent().len();
+
+    // three free, the fourth starts a wait
+    for _ in 0..4 {
+        let (status, _) = post(&app, "/auth/otp/email/send", Some(&token), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _) = post(&app, "/auth/otp/email/send", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(mailer.sent().len(), sent_before + 4);
+}
+
+#[actix_web::test]
+async fn secrets_stored_in_plaintext_are_encrypted_on_first_use() {
+    let clock = new_clock();
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_store(clock.clone(), store.clone()).await;
+    let (_, secret, _) = enroll(&app, &clock, "plaintext@example.com").await;
+
+    // stored the way it was before secrets were encrypted, with the url
+    let mut user = store.get_user_by_email("plaintext@example.com").unwrap();
+    user.otp_base32 = Some(secret.clone());
+    user.otp_auth_url = Some(format!(
+        "otpauth://totp/anusikh:plaintext@example.com?secret={secret}"
+    ));
+    store.delete_user(&user.id).unwrap();
+    store.add_user(user).unwrap();
+
+    clock.advance_steps(1);
+    let pre_auth = pre_auth_token(&app, "plaintext@example.com").await;
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": code_at(&secret, clock.now())}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let user = store.get_user_by_email("plaintext@example.com").unwrap();
+    assert_ne!(user.otp_base32.as_deref(), Some(secret.as_str()));
+    assert_eq!(user.otp_auth_url, None);
+}
+
+#[actix_web::test]
+async fn validate_accepts_each_recovery_code_once() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (_, _, recovery_codes) = enroll(&app, &clock, "recovery@example.com").await;
+
+    let pre_auth = pre_auth_token(&app, "recovery@example.com").await;
+    let body = json!({"recovery_code": recovery_codes[0]});
+    let (status, _) = post(&app, "/auth/otp/validate", Some(&pre_auth), body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let pre_auth = pre_auth_token(&app, "recovery@example.com").await;
+    let (status, _) = post(&app, "/auth/otp/validate", Some(&pre_auth), body).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn a_lost_authenticator_can_be_replaced_with_recovery_codes() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (_, _, recovery_codes) = enroll(&app, &clock, "lostphone@example.com").await;
+
+    let pre_auth = pre_auth_token(&app, "lostphone@example.com").await;
+    let (status, body) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"recovery_code": recovery_codes[0]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let token = body["jwt_token"].as_str().unwrap().to_owned();
+
+    // a recovery code is as good as a totp one for stepping up
+    let (status, _) = post(
+        &app,
+        "/auth/step-up",
+        Some(&token),
+        json!({"recovery_code": "not-a-code"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = post(
+        &app,
+        "/auth/step-up",
+        Some(&token),
+        json!({"recovery_code": recovery_codes[1]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let token = body["jwt_token"].as_str().unwrap().to_owned();
+
+    let (status, _) = post(&app, "/auth/otp/disable", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // and a new authenticator can be set up
+    let (status, body) = post(&app, "/auth/otp/generate", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let secret = body["secret"].as_str().unwrap().to_owned();
+    let (status, body) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code_at(&secret, clock.now())}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["recovery_codes"].as_array().unwrap().len(), 10);
+
+    clock.advance_steps(1);
+    let pre_auth = pre_auth_token(&app, "lostphone@example.com").await;
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": code_at(&secret, clock.now())}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn session_token<S>(app: &S, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let (status, body) = login(app, email, PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    body["jwt_token"].as_str().unwrap().to_owned()
+}
+
+// registers an admin and returns its session token
+async fn admin_token<S>(app: &S, store: &InMemoryStore, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    register(app, email).await;
+    let user = store.get_user_by_email(email).unwrap();
+    store.set_role(&user.id, ADMIN_ROLE).unwrap();
+    session_token(app, email).await
+}
+
+#[actix_web::test]
+async fn admin_routes_need_the_admin_role() {
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_store(new_clock(), store.clone()).await;
+    register(&app, "plain@example.com").await;
+    let token = session_token(&app, "plain@example.com").await;
+
+    let (status, body) = get(&app, "/admin/users", &token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["status"], "fail");
+
+    // the role is checked against the user too, so the old token stops
+    // working once it changes
+    let user = store.get_user_by_email("plain@example.com").unwrap();
+    store.set_role(&user.id, ADMIN_ROLE).unwrap();
+    let (status, _) = get(&app, "/admin/users", &token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let token = session_token(&app, "plain@example.com").await;
+    let (status, body) = get(&app, "/admin/users", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["users"][0]["email"], "plain@example.com");
+    assert_eq!(body["users"][0]["role"], ADMIN_ROLE);
+
+    // the timestamps are nullable columns, rows without them are listed too
+    let mut old = user.clone();
+    (old.id, old.email) = ("old".to_string(), "old@example.com".to_string());
+    (old.created_at, old.updated_at) = (None, None);
+    store.add_user(old).unwrap();
+    let (status, body) = get(&app, "/admin/users", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    let old = body["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|u| u["id"] == "old")
+        .unwrap();
+    assert!(old["created_at"].is_null());
+}
+
+#[actix_web::test]
+async fn admin_can_lock_and_unlock_an_account() {
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_store(new_clock(), store.clone()).await;
+    let admin = admin_token(&app, &store, "admin@example.com").await;
+    register(&app, "locked@example.com").await;
+    let token = session_token(&app, "locked@example.com").await;
+    let user_id = store.get_user_by_email("locked@example.com").unwrap().id;
+
+    let lock = format!("/admin/users/{user_id}/lock");
+    let (status, body) = post(&app, &lock, Some(&admin), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user"]["locked"], true);
+
+    let (status, _) = post(&app, "/auth/otp/generate", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "locked@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let unlock = format!("/admin/users/{user_id}/unlock");
+    let (status, body) = post(&app, &unlock, Some(&admin), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user"]["locked"], false);
+    session_token(&app, "locked@example.com").await;
+}
+
+#[actix_web::test]
+async fn admin_can_turn_off_a_users_2fa() {
+    let clock = new_clock();
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_store(clock.clone(), store.clone()).await;
+    let admin = admin_token(&app, &store, "admin@example.com").await;
+    enroll(&app, &clock, "lost@example.com").await;
+    pre_auth_token(&app, "lost@example.com").await;
+
+    let user_id = store.get_user_by_email("lost@example.com").unwrap().id;
+    let uri = format!("/admin/users/{user_id}/2fa/disable");
+    let (status, body) = post(&app, &uri, Some(&admin), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user"]["otp_enabled"], false);
+    let admin_id = store.get_user_by_email("admin@example.com").unwrap().id;
+    let (_, body) = get(&app, &format!("/admin/users/{user_id}/audit-log"), &admin).await;
+    assert_eq!(body["events"][0]["action"], "otp_disable");
+    assert_eq!(
+        body["events"][0]["detail"],
+        format!("every factor, by admin {admin_id}")
+    );
+
+    let (_, body) = login(&app, "lost@example.com", PASSWORD).await;
+    assert_eq!(body["2FA_enabled"], false);
+    assert!(body["jwt_token"].is_string());
+}
+
+#[actix_web::test]
+async fn sessions_can_be_listed_and_revoked() {
+    let app = init_app(new_clock()).await;
+    register(&app, "devices@example.com").await;
+    let login_from = |agent: &'static str| {
+        let req = TestRequest::post()
+            .uri("/auth/login")
+            .insert_header((header::USER_AGENT, agent))
+            .set_json(json!({"email": "devices@example.com", "password": PASSWORD}));
+        call(&app, req)
+    };
+    let (_, laptop) = login_from("laptop").await;
+    let (_, phone) = login_from("phone").await;
+    let laptop_token = laptop["jwt_token"].as_str().unwrap();
+    let phone_token = phone["jwt_token"].as_str().unwrap();
+
+    let (status, body) = get(&app, "/auth/sessions", laptop_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = body["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let phone_session = sessions
+        .iter()
+        .find(|s| s["user_agent"] == "phone")
+        .unwrap();
+    assert_eq!(phone_session["current"], false);
+    let laptop_session = sessions
+        .iter()
+        .find(|s| s["user_agent"] == "laptop")
+        .unwrap();
+    assert_eq!(laptop_session["current"], true);
+
+    // revoking the phone kills both its access and refresh token
+    let uri = format!(
+        "/auth/sessions/{}/revoke",
+        phone_session["id"].as_str().unwrap()
+    );
+    let (status, _) = post(&app, &uri, Some(laptop_token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = get(&app, "/auth/sessions", phone_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(
+        &app,
+        "/auth/token/refresh",
+        None,
+        json!({"refresh_token": phone["refresh_token"]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = post(
+        &app,
+        "/auth/sessions/revoke-all",
+        Some(laptop_token),
+        json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["revoked"], 1);
+    let (status, _) = get(&app, "/auth/sessions", laptop_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn sessions_of_other_users_cant_be_revoked() {
+    let app = init_app(new_clock()).await;
+    register(&app, "owner@example.com").await;
+    register(&app, "other@example.com").await;
+    let owner = session_token(&app, "owner@example.com").await;
+    let other = session_token(&app, "other@example.com").await;
+
+    let (_, body) = get(&app, "/auth/sessions", &owner).await;
+    let uri = format!(
+        "/auth/sessions/{}/revoke",
+        body["sessions"][0]["id"].as_str().unwrap()
+    );
+    let (status, _) = post(&app, &uri, Some(&other), json!({})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = get(&app, "/auth/sessions", &owner).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn logout_ends_the_session() {
+    let app = init_app(new_clock()).await;
+    register(&app, "logout@example.com").await;
+    let (_, body) = login(&app, "logout@example.com", PASSWORD).await;
+    let token = body["jwt_token"].as_str().unwrap();
+
+    let (status, _) = post(
+        &app,
+        "/auth/logout",
+        None,
+        json!({"refresh_token": body["refresh_token"]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = get(&app, "/auth/sessions", token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+// asks for a reset link and returns the token from the mail
+async fn reset_token<S>(app: &S, mailer: &InMemoryMailer, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let (status, _) = post(app, "/auth/password/forgot", None, json!({"email": email})).await;
+    assert_eq!(status, StatusCode::OK);
+    let body = mailer.sent().last().unwrap().body.clone();
+    let (_, token) = body.split_once("token=").unwrap();
+    token.split_whitespace().next().unwrap().to_owned()
+}
+
+#[actix_web::test]
+async fn reset_mails_are_only_sent_a_few_times_in_a_row() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_mailer(new_clock(), store, mailer.clone()).await;
+    register(&app, "forgetful@example.com").await;
+    let sent_before = mailer.sent().len();
+
+    // the same for an unknown address, so the 429 doesn't give away which
+    // one is registered
+    for email in ["forgetful@example.com", "unknown@example.com"] {
+        for _ in 0..4 {
+            let (status, _) =
+                post(&app, "/auth/password/forgot", None, json!({"email": email})).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = post(&app, "/auth/password/forgot", None, json!({"email": email})).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+    assert_eq!(mailer.sent().len(), sent_before + 4);
+}
+
+#[actix_web::test]
+async fn password_reset_ends_every_session() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let app = init_app_with_mailer(new_clock(), Arc::default(), mailer.clone()).await;
+    register(&app, "reset@example.com").await;
+    let (_, session) = login(&app, "reset@example.com", PASSWORD).await;
+    let access = session["jwt_token"].as_str().unwrap();
+
+    let token = reset_token(&app, &mailer, "reset@example.com").await;
+    let reset = json!({"token": token, "password": "a whole new passphrase"});
+    let (status, _) = post(&app, "/auth/password/reset", None, reset.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    // the link only works once
+    let (status, _) = post(&app, "/auth/password/reset", None, reset).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = get(&app, "/auth/sessions", access).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(
+        &app,
+        "/auth/token/refresh",
+        None,
+        json!({"refresh_token": session["refresh_token"]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "reset@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "reset@example.com", "a whole new passphrase").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // unknown emails get the same answer but no mail
+    let sent = mailer.sent().len();
+    let (status, _) = post(
+        &app,
+        "/auth/password/forgot",
+        None,
+        json!({"email": "nobody@example.com"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(mailer.sent().len(), sent);
+}
+
+#[actix_web::test]
+async fn password_reset_needs_the_second_factor_with_2fa() {
+    let clock = new_clock();
+    let mailer = Arc::new(InMemoryMailer::default());
+    let app = init_app_with_mailer(clock.clone(), Arc::default(), mailer.clone()).await;
+    let (_, _, recovery_codes) = enroll(&app, &clock, "reset2fa@example.com").await;
+
+    let token = reset_token(&app, &mailer, "reset2fa@example.com").await;
+    let password = "a whole new passphrase";
+    let (status, _) = post(
+        &app,
+        "/auth/password/reset",
+        None,
+        json!({"token": token, "password": password}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = post(
+        &app,
+        "/auth/password/reset",
+        None,
+        json!({"token": token, "password": password, "otp_token": "wrong"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    // the token wasn't used up by the failed tries
+    let (status, _) = post(
+        &app,
+        "/auth/password/reset",
+        None,
+        json!({"token": token, "password": password, "recovery_code": recovery_codes[0]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = login(&app, "reset2fa@example.com", password).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["2FA_enabled"], true);
+}
+
+#[actix_web::test]
+async fn refresh_rotates_the_token_and_a_replay_ends_the_session() {
+    let app = init_app(new_clock()).await;
+    register(&app, "refresh@example.com").await;
+    let (_, body) = login(&app, "refresh@example.com", PASSWORD).await;
+    let first = body["refresh_token"].as_str().unwrap().to_owned();
+
+    let (status, body) = post(
+        &app,
+        "/auth/token/refresh",
+        None,
+        json!({"refresh_token": first}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let second = body["refresh_token"].as_str().unwrap().to_owned();
+    let access = body["jwt_token"].as_str().unwrap().to_owned();
+    assert_ne!(first, second);
+    let (status, _) = get(&app, "/auth/sessions", &access).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the old token showing up again means it leaked, the whole family goes
+    let (status, _) = post(
+        &app,
+        "/auth/token/refresh",
+        None,
+        json!({"refresh_token": first}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(
+        &app,
+        "/auth/token/refresh",
+        None,
+        json!({"refresh_token": second}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = get(&app, "/auth/sessions", &access).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn audit_log_records_authentication_events() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    let (token, _, _) = enroll(&app, &clock, "audited@example.com").await;
+    // the sixth wrong password locks the account
+    for _ in 0..6 {
+        login(&app, "audited@example.com", "wrong").await;
+    }
+
+    let (status, body) = get(&app, "/auth/audit-log?limit=100", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    let events = body["events"].as_array().unwrap();
+    let actions: Vec<(&str, &str)> = events
+        .iter()
+        .map(|e| {
+            (
+                e["action"].as_str().unwrap(),
+                e["outcome"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    // newest first
+    assert_eq!(actions[0], ("lockout", "success"));
+    assert_eq!(actions[1], ("login", "failure"));
+    assert_eq!(events[1]["detail"], "wrong password");
+    assert_eq!(
+        actions[7..],
+        [
+            ("otp_verify", "success"),
+            ("otp_generate", "success"),
+            ("login", "success"),
+            ("email_verify", "success"),
+            ("register", "success"),
+        ]
+    );
+
+    let req = TestRequest::get()
+        .uri("/auth/audit-log/export")
+        .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/x-ndjson"
+    );
+    let body = test::read_body(resp).await;
+    let lines: Vec<Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), events.len());
+    assert_eq!(lines[0]["action"], "register");
+    assert_eq!(lines.last().unwrap()["action"], "lockout");
+}
+
+#[actix_web::test]
+async fn login_upgrades_weaker_password_hashes() {
+    let store = Arc::new(InMemoryStore::default());
+    let app = init_app_with_store(new_clock(), store.clone()).await;
+    register(&app, "legacy@example.com").await;
+
+    // a hash from before the cost was raised
+    let mut legacy = settings();
+    legacy.password_hash.memory_kib = 4096;
+    legacy.password_hash.iterations = 3;
+    let legacy_hash = password_scheme(&legacy).hash(PASSWORD).unwrap();
+    let user = store.get_user_by_email("legacy@example.com").unwrap();
+    assert!(store
+        .upgrade_password_hash(&user.id, &user.password, &legacy_hash)
+        .unwrap());
+
+    let (status, _) = login(&app, "legacy@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    let upgraded = store
+        .get_user_by_email("legacy@example.com")
+        .unwrap()
+        .password;
+    assert!(upgraded.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+
+    let (status, _) = login(&app, "legacy@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "legacy@example.com", "wrong").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn login_waits_for_the_email_to_be_verified() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let app = init_app_with_mailer(new_clock(), Arc::default(), mailer.clone()).await;
+    let (status, _) = register_unverified(&app, "not an address").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = register_unverified(&app, "new@example.com").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = login(&app, "new@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["message"], "email address is not verified");
+
+    let (status, _) = post(
+        &app,
+        "/auth/email/verify/resend",
+        None,
+        json!({"email": "new@example.com"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let sent = mailer.sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[1].to, "new@example.com");
+
+    let link_token = |body: &str| {
+        body.split("token=")
+            .nth(1)
+            .unwrap()
+            .split('\n')
+            .next()
+            .unwrap()
+            .to_owned()
+    };
+    let token = link_token(&sent[1].body);
+    let (status, _) = post(
+        &app,
+        "/auth/email/verify",
+        None,
+        json!({"token": token + "0"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(
+        &app,
+        "/auth/email/verify",
+        None,
+        json!({"token": link_token(&sent[0].body)}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = login(&app, "new@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["jwt_token"].is_string());
+
+    // nothing more to send once it is verified
+    post(
+        &app,
+        "/auth/email/verify/resend",
+        None,
+        json!({"email": "new@example.com"}),
+    )
+    .await;
+    assert_eq!(mailer.sent().len(), 2);
+}
+
+#[actix_web::test]
+async fn verification_links_are_only_resent_a_few_times_in_a_row() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let app = init_app_with_mailer(new_clock(), Arc::default(), mailer.clone()).await;
+    register_unverified(&app, "impatient@example.com").await;
+
+    let resend = || {
+        post(
+            &app,
+            "/auth/email/verify/resend",
+            None,
+            json!({"email": "impatient@example.com"}),
+        )
+    };
+    // three free, the fourth starts a wait
+    for _ in 0..4 {
+        let (status, _) = resend().await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _) = resend().await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    // the one from registering and four resent
+    assert_eq!(mailer.sent().len(), 5);
+}
+
+#[actix_web::test]
+async fn turning_off_2fa_needs_a_recent_totp_code() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    // the enrollment session was only ever proven with the password
+    let (token, secret, _) = enroll(&app, &clock, "step-up@example.com").await;
+
+    let (status, body) = post(&app, "/auth/otp/disable", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "step_up_required");
+    assert_eq!(body["method"], "totp");
+    let (status, _) = post(
+        &app,
+        "/auth/otp/recovery-codes/regenerate",
+        Some(&token),
+        json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = post(
+        &app,
+        "/auth/step-up",
+        Some(&token),
+        json!({"password": PASSWORD}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = post(
+        &app,
+        "/auth/step-up",
+        Some(&token),
+        json!({"token": "000000"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    clock.advance_steps(1);
+    let code = code_at(&secret, clock.now());
+    let (status, body) = post(&app, "/auth/step-up", Some(&token), json!({"token": code})).await;
+    assert_eq!(status, StatusCode::OK);
+    let stepped_up = body["jwt_token"].as_str().unwrap().to_owned();
+
+    let (status, body) = post(&app, "/auth/otp/disable", Some(&stepped_up), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["otp_disabled"], true);
+}
+
+#[actix_web::test]
+async fn tokens_are_signed_with_the_newest_key_and_checked_by_kid() {
+    let app = init_app(new_clock()).await;
+    register(&app, "jwks@example.com").await;
+    let session = session_token(&app, "jwks@example.com").await;
+    let header: Value = serde_json::from_slice(
+        &URL_SAFE_NO_PAD
+            .decode(session.split('.').next().unwrap())
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(header["alg"], "RS256");
+    assert_eq!(header["kid"], "2");
+
+    let (status, body) = call(&app, TestRequest::get().uri("/.well-known/jwks.json")).await;
+    assert_eq!(status, StatusCode::OK);
+    let kids: Vec<_> = body["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|k| k["kid"].as_str().unwrap())
+        .collect();
+    assert_eq!(kids, ["2", "1"]);
+
+    // tokens from before the rotation keep working while the old key is listed
+    let claims: TokenClaims = JwtKeys::from_keys(&settings().jwt_keys, 0)
+        .unwrap()
+        .verify(&session)
+        .unwrap();
+    let old_key = JwtKeys::from_keys("1:testdata/jwt-test-1.pem", 0).unwrap();
+    let (status, _) = get(&app, "/auth/sessions", &old_key.sign(claims.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // a new key can be published before it signs anything
+    let publish_first = JwtKeys::from_keys(&settings().jwt_keys, 1).unwrap();
+    assert_eq!(publish_first.jwks()["keys"].as_array().unwrap().len(), 2);
+    let token = publish_first.sign(claims.clone());
+    let header: Value = serde_json::from_slice(
+        &URL_SAFE_NO_PAD
+            .decode(token.split('.').next().unwrap())
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(header["kid"], "1");
+    assert!(JwtKeys::from_keys(&settings().jwt_keys, 3).is_err());
+
+    // without a kid there is no key to check it with
+    let hmac = Hmac::<Sha256>::new_from_slice(b"api-test").unwrap();
+    let (status, _) = get(
+        &app,
+        "/auth/sessions",
+        &claims.sign_with_key(&hmac).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn password_change_keeps_only_the_current_session() {
+    let app = init_app(new_clock()).await;
+    register(&app, "change@example.com").await;
+    let current = session_token(&app, "change@example.com").await;
+    let other = session_token(&app, "change@example.com").await;
+
+    let change = |current_password: &str| json!({"current_password": current_password, "new_password": "a new passphrase"});
+    let (status, _) = post(
+        &app,
+        "/auth/password/change",
+        Some(&current),
+        change("nope"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(
+        &app,
+        "/auth/password/change",
+        Some(&current),
+        change(PASSWORD),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = get(&app, "/auth/sessions", &current).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = get(&app, "/auth/sessions", &other).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "change@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "change@example.com", "a new passphrase").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn email_change_export_and_account_deletion() {
+    let mailer = Arc::new(InMemoryMailer::default());
+    let app = init_app_with_mailer(new_clock(), Arc::default(), mailer.clone()).await;
+    register(&app, "taken@example.com").await;
+    register(&app, "old@example.com").await;
+    let token = session_token(&app, "old@example.com").await;
+
+    let (status, _) = post(
+        &app,
+        "/auth/email/change",
+        Some(&token),
+        json!({"email": "taken@example.com"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, body) = post(
+        &app,
+        "/auth/email/change",
+        Some(&token),
+        json!({"email": "new@example.com"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user"]["email_verified"], false);
+    let sent = mailer.sent();
+    let recipients: Vec<_> = sent[sent.len() - 2..]
+        .iter()
+        .map(|m| m.to.as_str())
+        .collect();
+    assert_eq!(recipients, ["new@example.com", "old@example.com"]);
+
+    // the new address has to be verified first
+    let (status, _) = login(&app, "new@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let link = verification_token(&settings(), "new@example.com", Utc::now().timestamp() + 60);
+    let (status, _) = post(&app, "/auth/email/verify", None, json!({"token": link})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "new@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, export) = get(&app, "/auth/account/export", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(export["user"]["email"], "new@example.com");
+    assert_eq!(export["sessions"].as_array().unwrap().len(), 2);
+    assert!(export["audit_log"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|e| e["action"] == "email_change"));
+    assert!(export["user"].get("password").is_none());
+
+    let (status, _) = post(&app, "/auth/account/delete", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = get(&app, "/auth/account/export", &token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "new@example.com", PASSWORD).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn weak_passwords_are_rejected_with_field_errors() {
+    let app = init_app(new_clock()).await;
+    let register_with = |email: &'static str, password: &'static str| {
+        post(
+            &app,
+            "/auth/register",
+            None,
+            json!({"name": "test", "email": email, "password": password}),
+        )
+    };
+    let codes = |body: &Value| -> Vec<String> {
+        body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["code"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let (status, body) = register_with("weak@example.com", "").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errors"][0]["field"], "password");
+    assert_eq!(codes(&body), ["too_short"]);
+    let (_, body) = register_with("weak@example.com", "123456789012").await;
+    assert_eq!(codes(&body), ["banned_pattern"]);
+    let (_, body) = register_with("weak@example.com", "weak-but-long-enough").await;
+    assert_eq!(codes(&body), ["personal_info"]);
+    // the first and the last entry of the list
+    for breached in ["sunflower!!42", "iloveyou2024"] {
+        let (_, body) = register_with("weak@example.com", breached).await;
+        assert_eq!(codes(&body), ["breached"]);
+    }
+
+    register(&app, "strong@example.com").await;
+    let token = session_token(&app, "strong@example.com").await;
+    let (status, body) = post(
+        &app,
+        "/auth/password/change",
+        Some(&token),
+        json!({"current_password": PASSWORD, "new_password": "dragonfly-1987"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errors"][0]["field"], "new_password");
+    assert_eq!(codes(&body), ["breached"]);
+}
+
+#[actix_web::test]
+async fn enrollment_can_pick_a_stronger_totp_profile() {
+    let clock = new_clock();
+    let app = init_app(clock.clone()).await;
+    register(&app, "profile@example.com").await;
+    let token = session_token(&app, "profile@example.com").await;
+
+    let (status, body) = post(
+        &app,
+        "/auth/otp/generate",
+        Some(&token),
+        json!({"algorithm": "MD5", "digits": 10, "period": 45}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let fields: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["algorithm", "digits", "period"]);
+
+    // no body at all is the default profile
+    let req = TestRequest::post()
+        .uri("/auth/otp/generate")
+        .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+    let (status, body) = call(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["algorithm"], "SHA1");
+    assert_eq!(body["digits"], 6);
+    assert_eq!(body["period"], TOTP_STEP_SECS);
+
+    let (status, body) = post(
+        &app,
+        "/auth/otp/generate",
+        Some(&token),
+        json!({"algorithm": "sha256", "digits": 8, "period": 60}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let secret = body["secret"].as_str().unwrap().to_owned();
+    assert!(body["otp_auth_url"]
+        .as_str()
+        .unwrap()
+        .ends_with("&algorithm=SHA256&digits=8&period=60"));
+    let params = TotpParams {
+        algorithm: "SHA256".to_string(),
+        digits: 8,
+        period: 60,
+    };
+
+    // a code made with the defaults doesn't match the stored profile
+    let (status, _) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code_at(&secret, clock.now())}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let code = code_with(&params, &secret, clock.now());
+    assert_eq!(code.len(), 8);
+    let (status, _) = post(
+        &app,
+        "/auth/otp/verify",
+        Some(&token),
+        json!({"token": code}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // login checks codes with the stored profile too, one 60s step later
+    clock.advance_steps(2);
+    let pre_auth = pre_auth_token(&app, "profile@example.com").await;
+    let (status, _) = post(
+        &app,
+        "/auth/otp/validate",
+        Some(&pre_auth),
+        json!({"token": code_with(&params, &secret, clock.now())}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
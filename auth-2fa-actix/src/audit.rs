@@ -0,0 +1,103 @@
+use actix_web::web;
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    model::AuditEvent,
+    session::ClientInfo,
+    store::{run, UserStore},
+};
+
+// what an audit log entry is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    Register,
+    EmailVerify,
+    Login,
+    OtpGenerate,
+    OtpVerify,
+    OtpValidate,
+    // by the user or, for every factor at once, by an admin
+    OtpDisable,
+    EmailOtpEnable,
+    EmailOtpDisable,
+    PasswordChange,
+    EmailChange,
+    StepUp,
+    // logged without the user, whose entries are deleted along with them
+    AccountDelete,
+    // failed attempts locking the account or address, or an admin locking it
+    Lockout,
+    Unlock,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Register => "register",
+            AuditAction::EmailVerify => "email_verify",
+            AuditAction::Login => "login",
+            AuditAction::OtpGenerate => "otp_generate",
+            AuditAction::OtpVerify => "otp_verify",
+            AuditAction::OtpValidate => "otp_validate",
+            AuditAction::OtpDisable => "otp_disable",
+            AuditAction::EmailOtpEnable => "email_otp_enable",
+            AuditAction::EmailOtpDisable => "email_otp_disable",
+            AuditAction::PasswordChange => "password_change",
+            AuditAction::EmailChange => "email_change",
+            AuditAction::StepUp => "step_up",
+            AuditAction::AccountDelete => "account_delete",
+            AuditAction::Lockout => "lockout",
+            AuditAction::Unlock => "unlock",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+}
+
+impl AuditEvent {
+    pub fn new(
+        action: AuditAction,
+        outcome: Outcome,
+        user_id: Option<&str>,
+        client: &ClientInfo,
+    ) -> Self {
+        AuditEvent {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.map(str::to_owned),
+            action: action.as_str().to_string(),
+            outcome: outcome.as_str().to_string(),
+            ip: client.ip.to_owned(),
+            user_agent: client.user_agent.to_owned(),
+            detail: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    // why it failed or how it succeeded, never anything secret
+    pub fn detail(self, detail: impl Into<String>) -> Self {
+        AuditEvent {
+            detail: Some(detail.into()),
+            ..self
+        }
+    }
+}
+
+// for handlers outside a `run` closure, inside one call `add_audit_event`
+pub async fn record(data: &web::Data<dyn UserStore>, event: AuditEvent) -> Result<(), AppError> {
+    run(data, move |db| db.add_audit_event(event).map(|_| ())).await
+}
@@ -0,0 +1,188 @@
+use std::{collections::BTreeMap, fmt};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    UnknownKeyVersion(u32),
+    Malformed,
+    Decrypt,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::UnknownKeyVersion(v) => write!(f, "no encryption key with version {v}"),
+            CryptoError::Malformed => write!(f, "encrypted secret is malformed"),
+            CryptoError::Decrypt => write!(f, "could not decrypt secret"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+// encrypts otp secrets at rest, values are stored as `v<version>:<hex nonce+ciphertext>`
+// so old keys can stay around for decryption while new writes use the newest key
+pub struct SecretCipher {
+    keys: BTreeMap<u32, Aes256Gcm>,
+    current: u32,
+}
+
+impl SecretCipher {
+    // `keys` is a comma separated list of `<version>:<64 hex chars>`, the
+    // highest version is used for encryption
+    pub fn from_keys(keys: &str) -> Result<Self, String> {
+        let mut parsed = BTreeMap::new();
+        for entry in keys.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (version, key) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected <version>:<hex key>, got {entry}"))?;
+            let version: u32 = version
+                .parse()
+                .map_err(|_| format!("key version {version} is not a number"))?;
+            let key = hex::decode(key).map_err(|_| format!("key {version} is not hex"))?;
+            if key.len() != 32 {
+                return Err(format!("key {version} must be 32 bytes"));
+            }
+            parsed.insert(version, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+        }
+
+        let current = *parsed.keys().next_back().ok_or("no keys given")?;
+        Ok(SecretCipher {
+            keys: parsed,
+            current,
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[&self.current]
+            .encrypt(&nonce, plaintext.as_bytes())
+            .unwrap();
+        format!(
+            "v{}:{}{}",
+            self.current,
+            hex::encode(nonce),
+            hex::encode(ciphertext)
+        )
+    }
+
+    // also returns whether the value should be re-encrypted with the current
+    // key, which is the case for older keys and for secrets stored before
+    // encryption was added
+    pub fn decrypt(&self, stored: &str) -> Result<(String, bool), CryptoError> {
+        let (version, payload) = match stored.strip_prefix('v').and_then(|s| s.split_once(':')) {
+            Some((version, payload)) => (
+                version.parse::<u32>().map_err(|_| CryptoError::Malformed)?,
+                payload,
+            ),
+            // base32 never contains `:`, so this is a legacy plaintext secret
+            None => return Ok((stored.to_owned(), true)),
+        };
+
+        let cipher = self
+            .keys
+            .get(&version)
+            .ok_or(CryptoError::UnknownKeyVersion(version))?;
+        let payload = hex::decode(payload).map_err(|_| CryptoError::Malformed)?;
+        if payload.len() <= NONCE_LEN {
+            return Err(CryptoError::Malformed);
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Decrypt)?;
+        let plaintext = String::from_utf8(plaintext).map_err(|_| CryptoError::Malformed)?;
+
+        Ok((plaintext, version != self.current))
+    }
+}
+
+// for random codes a keyed sha256 is enough, and unlike argon2 it lets us
+// look the code up by its hash
+pub fn keyed_hash(hash_secret: &str, value: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(hash_secret.as_bytes()).unwrap();
+    mac.update(value.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn cipher(keys: &[(u32, u8)]) -> SecretCipher {
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|(version, byte)| format!("{version}:{}", hex::encode([*byte; 32])))
+            .collect();
+        SecretCipher::from_keys(&keys.join(",")).unwrap()
+    }
+
+    #[test]
+    fn round_trips_with_the_current_key() {
+        let cipher = cipher(&[(1, 0x11), (2, 0x22)]);
+        let stored = cipher.encrypt(SECRET);
+        assert!(stored.starts_with("v2:"));
+        assert!(!stored.contains(SECRET));
+        assert_eq!(
+            cipher.decrypt(&stored).unwrap(),
+            (SECRET.to_string(), false)
+        );
+    }
+
+    #[test]
+    fn decrypts_with_an_older_key_and_asks_for_re_encryption() {
+        let stored = cipher(&[(1, 0x11)]).encrypt(SECRET);
+        let rotated = cipher(&[(1, 0x11), (2, 0x22)]);
+        assert_eq!(
+            rotated.decrypt(&stored).unwrap(),
+            (SECRET.to_string(), true)
+        );
+
+        let re_encrypted = rotated.encrypt(SECRET);
+        assert!(re_encrypted.starts_with("v2:"));
+        assert_eq!(
+            rotated.decrypt(&re_encrypted).unwrap(),
+            (SECRET.to_string(), false)
+        );
+    }
+
+    #[test]
+    fn passes_legacy_plaintext_through() {
+        let cipher = cipher(&[(1, 0x11)]);
+        assert_eq!(cipher.decrypt(SECRET).unwrap(), (SECRET.to_string(), true));
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_tampering() {
+        let stored = cipher(&[(3, 0x33)]).encrypt(SECRET);
+        let cipher = cipher(&[(1, 0x11), (2, 0x22)]);
+        assert!(matches!(
+            cipher.decrypt(&stored),
+            Err(CryptoError::UnknownKeyVersion(3))
+        ));
+
+        let stored = cipher.encrypt(SECRET);
+        let mut tampered = stored.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(matches!(
+            cipher.decrypt(&tampered),
+            Err(CryptoError::Decrypt)
+        ));
+        assert!(matches!(
+            cipher.decrypt("v2:zz"),
+            Err(CryptoError::Malformed)
+        ));
+    }
+}
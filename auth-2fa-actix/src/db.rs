@@ -1,11 +1,23 @@
 use diesel::dsl::exists;
-use diesel::r2d2::{self, ConnectionManager};
-use diesel::result::Error;
+use diesel::migration;
+use diesel::r2d2::{self, ConnectionManager, PooledConnection};
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::Text;
 use diesel::{prelude::*, select};
-use dotenv::dotenv;
 
-use crate::model::User;
+use crate::error::AppError;
+use crate::migrations::{check_migrations, run_migrations};
+use crate::model::{
+    AuditEvent, EmailOtp, LoginAttempt, PasswordReset, RecoveryCode, RefreshToken, SecondFactor,
+    Session, User,
+};
+use crate::otp::TotpParams;
 use crate::schema::users::dsl::*;
+use crate::schema::{
+    audit_events, email_otps, login_attempts, password_resets, recovery_codes, refresh_tokens,
+    sessions,
+};
+use crate::store::UserStore;
 
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
@@ -14,10 +26,7 @@ pub struct Database {
 }
 
 impl Database {
-    pub fn new() -> Self {
-        dotenv().ok();
-        let database_url: String =
-            std::env::var("DATABASE_URL").expect("DATABASE_URL is not available, add env");
+    pub fn new(database_url: &str) -> Self {
         let manager = ConnectionManager::<PgConnection>::new(database_url);
         let pool: DbPool = r2d2::Pool::builder()
             .build(manager)
@@ -25,67 +34,612 @@ impl Database {
         Database { pool }
     }
 
-    pub fn if_user_exists(&self, entered_email: &str) -> bool {
-        let res = select(exists(users.filter(email.eq(entered_email))))
-            .get_result::<bool>(&mut self.pool.get().unwrap())
-            .expect("something went wrong");
-        res
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, AppError> {
+        Ok(self.pool.get()?)
     }
 
-    pub fn if_user_exists_userid(&self, user_id: &str) -> bool {
-        let res = select(exists(users.filter(id.eq(user_id))))
-            .get_result::<bool>(&mut self.pool.get().unwrap())
-            .expect("something went wrong");
-        res
+    pub fn run_migrations(&self) -> migration::Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        run_migrations(&mut conn)
     }
 
-    pub fn add_user(&self, user: User) -> Result<usize, Error> {
-        let res = diesel::insert_into(users)
+    pub fn check_migrations(&self) -> migration::Result<()> {
+        let mut conn = self.pool.get()?;
+        check_migrations(&mut conn)
+    }
+}
+
+// every session of the user along with its refresh tokens, run inside the
+// caller's transaction
+fn revoke_user_sessions(
+    conn: &mut PgConnection,
+    user_id: &str,
+    date_time: chrono::NaiveDateTime,
+) -> Result<usize, AppError> {
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(date_time))
+    .execute(conn)?;
+    diesel::update(
+        sessions::table
+            .filter(sessions::user_id.eq(user_id))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(date_time))
+    .execute(conn)
+    .map_err(AppError::from)
+}
+
+// emails are looked up the way the unique index compares them, so an
+// address works whatever case it is typed in
+sql_function!(fn lower(x: Text) -> Text);
+
+// the unique index on lower(email) catches registrations and email changes
+// that raced past the `if_user_exists` check
+fn email_conflict(e: diesel::result::Error, address: &str) -> AppError {
+    match e {
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            AppError::Conflict(format!("user with email {address} already exists"))
+        }
+        e => AppError::from(e),
+    }
+}
+
+impl UserStore for Database {
+    fn if_user_exists(&self, entered_email: &str) -> Result<bool, AppError> {
+        Ok(
+            select(exists(users.filter(lower(email).eq(lower(entered_email)))))
+                .get_result::<bool>(&mut self.conn()?)?,
+        )
+    }
+
+    fn if_user_exists_userid(&self, user_id: &str) -> Result<bool, AppError> {
+        Ok(select(exists(users.filter(id.eq(user_id)))).get_result::<bool>(&mut self.conn()?)?)
+    }
+
+    fn add_user(&self, user: User) -> Result<usize, AppError> {
+        diesel::insert_into(users)
             .values(&user)
             .returning(User::as_returning())
-            .execute(&mut self.pool.get().unwrap());
-        res
+            .execute(&mut self.conn()?)
+            .map_err(|e| email_conflict(e, &user.email))
     }
 
-    pub fn get_user_by_email(&self, user_email: &str) -> Result<User, Error> {
-        let res = users
-            .filter(email.eq(user_email))
-            .get_result::<User>(&mut self.pool.get().unwrap());
-        res
+    fn get_user_by_email(&self, user_email: &str) -> Result<User, AppError> {
+        users
+            .filter(lower(email).eq(lower(user_email)))
+            .get_result::<User>(&mut self.conn()?)
+            .map_err(AppError::from)
     }
 
-    pub fn get_user_by_userid(&self, user_id: &str) -> Result<User, Error> {
-        let res = users
+    fn get_user_by_userid(&self, user_id: &str) -> Result<User, AppError> {
+        users
             .filter(id.eq(user_id))
-            .get_result::<User>(&mut self.pool.get().unwrap());
-        res
+            .get_result::<User>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn upgrade_password_hash(
+        &self,
+        user_id: &str,
+        old_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool, AppError> {
+        diesel::update(users.find(user_id).filter(password.eq(old_hash)))
+            .set(password.eq(new_hash))
+            .execute(&mut self.conn()?)
+            .map(|updated| updated == 1)
+            .map_err(AppError::from)
+    }
+
+    fn change_password(
+        &self,
+        user_id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        keep_session_id: &str,
+    ) -> Result<bool, AppError> {
+        self.conn()?.transaction(|conn| {
+            let date_time = chrono::Utc::now().naive_utc();
+            let changed = diesel::update(users.find(user_id).filter(password.eq(old_hash)))
+                .set((password.eq(new_hash), updated_at.eq(date_time)))
+                .execute(conn)?;
+            if changed != 1 {
+                return Ok(false);
+            }
+
+            diesel::update(
+                refresh_tokens::table
+                    .filter(refresh_tokens::user_id.eq(user_id))
+                    .filter(refresh_tokens::family_id.ne(keep_session_id))
+                    .filter(refresh_tokens::revoked_at.is_null()),
+            )
+            .set(refresh_tokens::revoked_at.eq(date_time))
+            .execute(conn)?;
+            diesel::update(
+                sessions::table
+                    .filter(sessions::user_id.eq(user_id))
+                    .filter(sessions::id.ne(keep_session_id))
+                    .filter(sessions::revoked_at.is_null()),
+            )
+            .set(sessions::revoked_at.eq(date_time))
+            .execute(conn)?;
+            Ok(true)
+        })
+    }
+
+    fn change_email(&self, user_id: &str, new_email: &str) -> Result<User, AppError> {
+        diesel::update(users.find(user_id))
+            .set((
+                email.eq(new_email),
+                email_verified.eq(false),
+                updated_at.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .get_result::<User>(&mut self.conn()?)
+            .map_err(|e| email_conflict(e, new_email))
+    }
+
+    // the other tables go with it through their ON DELETE CASCADE
+    fn delete_user(&self, user_id: &str) -> Result<usize, AppError> {
+        diesel::delete(users.find(user_id))
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn list_users(&self, offset: i64, limit: i64) -> Result<Vec<User>, AppError> {
+        users
+            .order((created_at.asc(), id.asc()))
+            .offset(offset)
+            .limit(limit)
+            .load::<User>(&mut self.conn()?)
+            .map_err(AppError::from)
     }
 
-    pub fn update_totp_for_user(
+    fn set_role(&self, user_id: &str, new_role: &str) -> Result<User, AppError> {
+        diesel::update(users.find(user_id))
+            .set(role.eq(new_role))
+            .get_result::<User>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn set_locked(&self, user_id: &str, locked: bool) -> Result<User, AppError> {
+        if !locked {
+            return diesel::update(users.find(user_id))
+                .set(locked_at.eq(None::<chrono::NaiveDateTime>))
+                .get_result::<User>(&mut self.conn()?)
+                .map_err(AppError::from);
+        }
+
+        let date_time = chrono::Utc::now().naive_utc();
+        self.conn()?.transaction(|conn| {
+            let user = diesel::update(users.find(user_id))
+                .set((locked_at.eq(date_time), sessions_valid_after.eq(date_time)))
+                .get_result::<User>(conn)?;
+            revoke_user_sessions(conn, user_id, date_time)?;
+            Ok(user)
+        })
+    }
+
+    fn disable_second_factors(&self, user_id: &str) -> Result<User, AppError> {
+        self.conn()?.transaction(|conn| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)?;
+            diesel::delete(email_otps::table.find(user_id)).execute(conn)?;
+            diesel::update(users.find(user_id))
+                .set((
+                    otp_enabled.eq(false),
+                    otp_verified.eq(false),
+                    otp_base32.eq(None::<String>),
+                    otp_auth_url.eq(None::<String>),
+                    otp_last_used_step.eq(None::<i64>),
+                    otp_algorithm.eq(None::<String>),
+                    otp_digits.eq(None::<i32>),
+                    otp_period.eq(None::<i32>),
+                    email_otp_enabled.eq(false),
+                    default_factor.eq(SecondFactor::Totp.as_str()),
+                ))
+                .get_result::<User>(conn)
+                .map_err(AppError::from)
+        })
+    }
+
+    // otp_auth_url is no longer stored since it carries the plaintext secret,
+    // it is rebuilt from the secret when needed
+    fn update_totp_for_user(
         &self,
         user_id: &str,
-        gen_otp_base32: &str,
-        gen_otp_auth_url: &str,
+        secret: Option<(&str, &TotpParams)>,
         verified: bool,
-    ) -> Result<User, Error> {
+    ) -> Result<User, AppError> {
         match verified {
-            true => {
-                let res = diesel::update(users.find(&user_id))
-                    .set((otp_enabled.eq(verified), otp_verified.eq(verified)))
-                    .get_result::<User>(&mut self.pool.get().unwrap());
-                res
+            true => diesel::update(users.find(&user_id))
+                .set((otp_enabled.eq(verified), otp_verified.eq(verified)))
+                .get_result::<User>(&mut self.conn()?)
+                .map_err(AppError::from),
+            false => diesel::update(users.find(&user_id))
+                .set((
+                    otp_base32.eq(secret.map(|(encrypted, _)| encrypted)),
+                    otp_auth_url.eq(None::<String>),
+                    otp_enabled.eq(verified),
+                    otp_verified.eq(verified),
+                    otp_last_used_step.eq(None::<i64>),
+                    otp_algorithm.eq(secret.map(|(_, params)| params.algorithm.as_str())),
+                    otp_digits.eq(secret.map(|(_, params)| params.digits as i32)),
+                    otp_period.eq(secret.map(|(_, params)| params.period as i32)),
+                ))
+                .get_result::<User>(&mut self.conn()?)
+                .map_err(AppError::from),
+        }
+    }
+
+    // used to re-encrypt the secret after a key rotation, also drops an
+    // otp_auth_url left from before secrets were encrypted
+    fn update_otp_secret(
+        &self,
+        user_id: &str,
+        encrypted_otp_base32: &str,
+    ) -> Result<usize, AppError> {
+        diesel::update(users.find(user_id))
+            .set((
+                otp_base32.eq(encrypted_otp_base32),
+                otp_auth_url.eq(None::<String>),
+            ))
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    // only moves the step forward, so a code that was already used (or an older
+    // one) is rejected even when two requests race
+    fn record_totp_step(&self, user_id: &str, step: i64) -> Result<bool, AppError> {
+        let updated = diesel::update(
+            users
+                .find(user_id)
+                .filter(otp_last_used_step.is_null().or(otp_last_used_step.lt(step))),
+        )
+        .set(otp_last_used_step.eq(step))
+        .execute(&mut self.conn()?)?;
+        Ok(updated == 1)
+    }
+
+    // swaps out every recovery code of the user for the new set
+    fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        codes: Vec<RecoveryCode>,
+    ) -> Result<usize, AppError> {
+        self.conn()?.transaction(|conn| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)?;
+            Ok(diesel::insert_into(recovery_codes::table)
+                .values(&codes)
+                .execute(conn)?)
+        })
+    }
+
+    // marks an unused code as used, returns false if there was none to use
+    fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool, AppError> {
+        let updated = diesel::update(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(user_id))
+                .filter(recovery_codes::code_hash.eq(code_hash))
+                .filter(recovery_codes::used_at.is_null()),
+        )
+        .set(recovery_codes::used_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(&mut self.conn()?)?;
+        Ok(updated == 1)
+    }
+
+    fn count_unused_recovery_codes(&self, user_id: &str) -> Result<i64, AppError> {
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(user_id))
+            .filter(recovery_codes::used_at.is_null())
+            .count()
+            .get_result::<i64>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn delete_recovery_codes(&self, user_id: &str) -> Result<usize, AppError> {
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken) -> Result<usize, AppError> {
+        diesel::insert_into(refresh_tokens::table)
+            .values(&token)
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn get_refresh_token_by_hash(&self, hash: &str) -> Result<RefreshToken, AppError> {
+        refresh_tokens::table
+            .filter(refresh_tokens::token_hash.eq(hash))
+            .get_result::<RefreshToken>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    // revokes the old token and stores its replacement in one go, returns
+    // false if the old token was already revoked by a concurrent refresh
+    fn rotate_refresh_token(&self, old_id: &str, new: RefreshToken) -> Result<bool, AppError> {
+        self.conn()?.transaction(|conn| {
+            let revoked = diesel::update(
+                refresh_tokens::table
+                    .find(old_id)
+                    .filter(refresh_tokens::revoked_at.is_null()),
+            )
+            .set(refresh_tokens::revoked_at.eq(chrono::Utc::now().naive_utc()))
+            .execute(conn)?;
+            if revoked != 1 {
+                return Ok(false);
             }
-            false => {
-                let res: Result<User, Error> = diesel::update(users.find(&user_id))
-                    .set((
-                        otp_base32.eq(&gen_otp_base32),
-                        otp_auth_url.eq(&gen_otp_auth_url),
-                        otp_enabled.eq(verified),
-                        otp_verified.eq(verified),
-                    ))
-                    .get_result::<User>(&mut self.pool.get().unwrap());
-                res
+
+            diesel::insert_into(refresh_tokens::table)
+                .values(&new)
+                .execute(conn)?;
+            Ok(true)
+        })
+    }
+
+    fn add_session(&self, session: Session) -> Result<usize, AppError> {
+        diesel::insert_into(sessions::table)
+            .values(&session)
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn get_session(&self, session_id: &str) -> Result<Option<Session>, AppError> {
+        sessions::table
+            .find(session_id)
+            .get_result::<Session>(&mut self.conn()?)
+            .optional()
+            .map_err(AppError::from)
+    }
+
+    fn list_sessions(&self, user_id: &str) -> Result<Vec<Session>, AppError> {
+        sessions::table
+            .filter(sessions::user_id.eq(user_id))
+            .filter(sessions::revoked_at.is_null())
+            .order(sessions::last_seen_at.desc())
+            .load::<Session>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn touch_session(
+        &self,
+        session_id: &str,
+        last_seen_at: chrono::NaiveDateTime,
+    ) -> Result<usize, AppError> {
+        diesel::update(sessions::table.find(session_id))
+            .set(sessions::last_seen_at.eq(last_seen_at))
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    // refresh tokens of a session share its id as their family
+    fn revoke_session(&self, session_id: &str) -> Result<usize, AppError> {
+        let date_time = chrono::Utc::now().naive_utc();
+        self.conn()?.transaction(|conn| {
+            diesel::update(
+                refresh_tokens::table
+                    .filter(refresh_tokens::family_id.eq(session_id))
+                    .filter(refresh_tokens::revoked_at.is_null()),
+            )
+            .set(refresh_tokens::revoked_at.eq(date_time))
+            .execute(conn)?;
+            diesel::update(
+                sessions::table
+                    .find(session_id)
+                    .filter(sessions::revoked_at.is_null()),
+            )
+            .set(sessions::revoked_at.eq(date_time))
+            .execute(conn)
+            .map_err(AppError::from)
+        })
+    }
+
+    fn revoke_all_sessions(&self, user_id: &str) -> Result<usize, AppError> {
+        let date_time = chrono::Utc::now().naive_utc();
+        self.conn()?
+            .transaction(|conn| revoke_user_sessions(conn, user_id, date_time))
+    }
+
+    fn export_sessions(&self, user_id: &str) -> Result<Vec<Session>, AppError> {
+        sessions::table
+            .filter(sessions::user_id.eq(user_id))
+            .order(sessions::created_at.asc())
+            .load::<Session>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn step_up_session(
+        &self,
+        session_id: &str,
+        amr: &str,
+        auth_time: chrono::NaiveDateTime,
+    ) -> Result<Session, AppError> {
+        diesel::update(sessions::table.find(session_id))
+            .set((sessions::amr.eq(amr), sessions::auth_time.eq(auth_time)))
+            .get_result::<Session>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn add_audit_event(&self, event: AuditEvent) -> Result<usize, AppError> {
+        diesel::insert_into(audit_events::table)
+            .values(&event)
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn list_audit_events(
+        &self,
+        user_id: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AppError> {
+        audit_events::table
+            .filter(audit_events::user_id.eq(user_id))
+            .order((audit_events::created_at.desc(), audit_events::id.desc()))
+            .offset(offset)
+            .limit(limit)
+            .load::<AuditEvent>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn export_audit_events(&self, user_id: &str) -> Result<Vec<AuditEvent>, AppError> {
+        audit_events::table
+            .filter(audit_events::user_id.eq(user_id))
+            .order((audit_events::created_at.asc(), audit_events::id.asc()))
+            .load::<AuditEvent>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn get_login_attempt(&self, key: &str) -> Result<Option<LoginAttempt>, AppError> {
+        login_attempts::table
+            .find(key)
+            .get_result::<LoginAttempt>(&mut self.conn()?)
+            .optional()
+            .map_err(AppError::from)
+    }
+
+    // bumps the failure counter, failures older than `reset_before` start the
+    // count over, `lock_for` decides how long the key is locked for
+    fn record_failed_attempt(
+        &self,
+        key: &str,
+        reset_before: chrono::NaiveDateTime,
+        lock_for: &dyn Fn(i32) -> Option<chrono::Duration>,
+    ) -> Result<LoginAttempt, AppError> {
+        self.conn()?.transaction(|conn| {
+            let existing = login_attempts::table
+                .find(key)
+                .for_update()
+                .get_result::<LoginAttempt>(conn)
+                .optional()?;
+
+            let date_time = chrono::Utc::now().naive_utc();
+            let failures = match existing {
+                Some(attempt) if attempt.last_failure_at > reset_before => attempt.failures + 1,
+                _ => 1,
+            };
+            let attempt = LoginAttempt {
+                attempt_key: key.to_owned(),
+                failures,
+                locked_until: lock_for(failures).map(|d| date_time + d),
+                last_failure_at: date_time,
+            };
+
+            Ok(diesel::insert_into(login_attempts::table)
+                .values(&attempt)
+                .on_conflict(login_attempts::attempt_key)
+                .do_update()
+                .set(&attempt)
+                .get_result::<LoginAttempt>(conn)?)
+        })
+    }
+
+    fn clear_login_attempt(&self, key: &str) -> Result<usize, AppError> {
+        diesel::delete(login_attempts::table.find(key))
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn set_email_otp(&self, otp: EmailOtp) -> Result<usize, AppError> {
+        diesel::insert_into(email_otps::table)
+            .values(&otp)
+            .on_conflict(email_otps::user_id)
+            .do_update()
+            .set(&otp)
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    // codes are single use, a matching unexpired code is deleted
+    fn consume_email_otp(&self, user_id: &str, code_hash: &str) -> Result<bool, AppError> {
+        let deleted = diesel::delete(
+            email_otps::table
+                .find(user_id)
+                .filter(email_otps::code_hash.eq(code_hash))
+                .filter(email_otps::expires_at.gt(chrono::Utc::now().naive_utc())),
+        )
+        .execute(&mut self.conn()?)?;
+        Ok(deleted == 1)
+    }
+
+    fn set_email_otp_enabled(
+        &self,
+        user_id: &str,
+        enabled: bool,
+        factor: &str,
+    ) -> Result<User, AppError> {
+        diesel::update(users.find(user_id))
+            .set((email_otp_enabled.eq(enabled), default_factor.eq(factor)))
+            .get_result::<User>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn set_default_factor(&self, user_id: &str, factor: &str) -> Result<User, AppError> {
+        diesel::update(users.find(user_id))
+            .set(default_factor.eq(factor))
+            .get_result::<User>(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn verify_email(&self, address: &str) -> Result<Option<User>, AppError> {
+        diesel::update(users.filter(lower(email).eq(lower(address))))
+            .set(email_verified.eq(true))
+            .get_result::<User>(&mut self.conn()?)
+            .optional()
+            .map_err(AppError::from)
+    }
+
+    fn add_password_reset(&self, reset: PasswordReset) -> Result<usize, AppError> {
+        diesel::insert_into(password_resets::table)
+            .values(&reset)
+            .execute(&mut self.conn()?)
+            .map_err(AppError::from)
+    }
+
+    fn get_password_reset_by_hash(&self, hash: &str) -> Result<Option<PasswordReset>, AppError> {
+        password_resets::table
+            .filter(password_resets::token_hash.eq(hash))
+            .get_result::<PasswordReset>(&mut self.conn()?)
+            .optional()
+            .map_err(AppError::from)
+    }
+
+    // uses up the reset token, sets the new password hash and ends every
+    // existing session, returns false if the token was used in the meantime
+    fn reset_password(
+        &self,
+        reset_id: &str,
+        user_id: &str,
+        password_hash: &str,
+    ) -> Result<bool, AppError> {
+        self.conn()?.transaction(|conn| {
+            let date_time = chrono::Utc::now().naive_utc();
+            let used = diesel::update(
+                password_resets::table
+                    .find(reset_id)
+                    .filter(password_resets::used_at.is_null()),
+            )
+            .set(password_resets::used_at.eq(date_time))
+            .execute(conn)?;
+            if used != 1 {
+                return Ok(false);
             }
-        }
+
+            diesel::update(users.find(user_id))
+                .set((
+                    password.eq(password_hash),
+                    sessions_valid_after.eq(date_time),
+                    updated_at.eq(date_time),
+                ))
+                .execute(conn)?;
+            revoke_user_sessions(conn, user_id, date_time)?;
+            Ok(true)
+        })
     }
 }
@@ -0,0 +1,48 @@
+use chrono::{Duration, Utc};
+use rand::Rng;
+
+use crate::{
+    crypto::keyed_hash,
+    error::AppError,
+    mailer::{Mail, Mailer},
+    model::{EmailOtp, User},
+    settings::Settings,
+    store::UserStore,
+};
+
+// sends a fresh code to the user's address, replacing any earlier one
+pub fn send_email_otp(
+    data: &dyn UserStore,
+    mailer: &dyn Mailer,
+    settings: &Settings,
+    user: &User,
+) -> Result<(), AppError> {
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let ttl_secs = settings.tokens.email_otp_ttl_secs;
+    let date_time = Utc::now().naive_utc();
+    data.set_email_otp(EmailOtp {
+        user_id: user.id.to_owned(),
+        code_hash: keyed_hash(&settings.hash_secret, &code),
+        expires_at: date_time + Duration::seconds(ttl_secs),
+        created_at: date_time,
+    })?;
+
+    mailer.send(Mail {
+        to: user.email.to_owned(),
+        subject: "Your verification code".to_string(),
+        body: format!(
+            "Your verification code is {code}. It expires in {} minutes.",
+            ttl_secs / 60
+        ),
+    })?;
+    Ok(())
+}
+
+pub fn check_email_otp(
+    data: &dyn UserStore,
+    settings: &Settings,
+    user_id: &str,
+    code: &str,
+) -> Result<bool, AppError> {
+    data.consume_email_otp(user_id, &keyed_hash(&settings.hash_secret, code.trim()))
+}
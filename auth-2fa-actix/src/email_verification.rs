@@ -0,0 +1,60 @@
+use chrono::Utc;
+use constant_time_eq::constant_time_eq;
+
+use crate::{
+    crypto::keyed_hash,
+    error::AppError,
+    mailer::{Mail, Mailer},
+    model::User,
+    settings::Settings,
+};
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+// the token carries the address and its expiry along with a keyed hash of
+// both, so nothing is stored for it. it only ever verifies the address it was
+// sent to, once the user changes their email older links stop working
+pub fn verification_token(settings: &Settings, email: &str, expires_at: i64) -> String {
+    format!(
+        "{}.{expires_at}.{}",
+        base32::encode(ALPHABET, email.as_bytes()),
+        signature(settings, email, expires_at)
+    )
+}
+
+fn signature(settings: &Settings, email: &str, expires_at: i64) -> String {
+    keyed_hash(
+        &settings.hash_secret,
+        &format!("verify-email:{email}:{expires_at}"),
+    )
+}
+
+// the address the token was issued for, if it is genuine and hasn't expired
+pub fn check_verification_token(settings: &Settings, token: &str, now: i64) -> Option<String> {
+    let mut parts = token.trim().splitn(3, '.');
+    let email = base32::decode(ALPHABET, parts.next()?).and_then(|e| String::from_utf8(e).ok())?;
+    let expires_at: i64 = parts.next()?.parse().ok()?;
+    let expected = signature(settings, &email, expires_at);
+    let genuine = constant_time_eq(parts.next()?.as_bytes(), expected.as_bytes());
+    (genuine && expires_at > now).then_some(email)
+}
+
+pub fn send_email_verification(
+    mailer: &dyn Mailer,
+    settings: &Settings,
+    user: &User,
+) -> Result<(), AppError> {
+    let ttl_secs = settings.tokens.email_verification_ttl_secs;
+    let token = verification_token(settings, &user.email, Utc::now().timestamp() + ttl_secs);
+
+    mailer.send(Mail {
+        to: user.email.to_owned(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Confirm your email address at {}?token={token}\nThe link expires in {} hours. If you didn't sign up, ignore this mail.",
+            settings.email_verification_url,
+            ttl_secs / 3600
+        ),
+    })?;
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{crypto::CryptoError, mailer::MailError, response::GenericResponse};
+
+// one rule a request field breaks, `code` is for clients to branch on
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    Pool(String),
+    Query(diesel::result::Error),
+    NotFound(String),
+    Crypto(String),
+    Mail(String),
+    Validation(String),
+    InvalidFields(Vec<FieldError>),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    TooManyRequests { retry_after: i64 },
+    // the route wants the user to prove themselves again with `method`
+    // through /auth/step-up, the token's auth_time is older than `max_age`
+    StepUpRequired { method: &'static str, max_age: i64 },
+    Internal(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Pool(e) => write!(f, "connection pool error: {e}"),
+            AppError::Query(e) => write!(f, "query error: {e}"),
+            AppError::NotFound(what) => write!(f, "{what} not found"),
+            AppError::Crypto(e) => write!(f, "crypto error: {e}"),
+            AppError::Mail(e) => write!(f, "mail error: {e}"),
+            AppError::Validation(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::Conflict(msg) => write!(f, "{msg}"),
+            AppError::InvalidFields(errors) => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("{} {}", e.field, e.message))
+                    .collect();
+                write!(f, "{}", errors.join(", "))
+            }
+            AppError::TooManyRequests { retry_after } => {
+                write!(f, "too many failed attempts, retry after {retry_after}s")
+            }
+            AppError::StepUpRequired { method, max_age } => write!(
+                f,
+                "step-up required, authenticate with {method} again (within {max_age}s)"
+            ),
+            AppError::Internal(e) => write!(f, "internal error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl AppError {
+    // what the client gets to see, internal failures are only logged
+    fn public_message(&self) -> String {
+        match self {
+            AppError::Pool(_)
+            | AppError::Query(_)
+            | AppError::Crypto(_)
+            | AppError::Mail(_)
+            | AppError::Internal(_) => "something went wrong".to_string(),
+            AppError::TooManyRequests { .. } => {
+                "too many failed attempts, try again later".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Query(_)
+            | AppError::Crypto(_)
+            | AppError::Mail(_)
+            | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Validation(_) | AppError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::StepUpRequired { .. } => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            log::error!("{self}");
+        }
+
+        let mut resp = HttpResponse::build(status);
+        match self {
+            AppError::TooManyRequests { retry_after } => {
+                resp.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+            }
+            // machine readable so clients can prompt for the code and retry,
+            // the header follows RFC 9470
+            AppError::StepUpRequired { method, max_age } => {
+                resp.insert_header((
+                    header::WWW_AUTHENTICATE,
+                    format!("Bearer error=\"insufficient_user_authentication\", max_age={max_age}"),
+                ));
+                return resp.json(json!({
+                    "status": "fail",
+                    "error": "step_up_required",
+                    "message": self.public_message(),
+                    "method": method,
+                    "max_age": max_age,
+                }));
+            }
+            AppError::InvalidFields(errors) => {
+                return resp.json(json!({
+                    "status": "fail",
+                    "message": self.public_message(),
+                    "errors": errors,
+                }));
+            }
+            _ => {}
+        }
+        resp.json(GenericResponse {
+            status: "fail".to_string(),
+            message: self.public_message(),
+        })
+    }
+}
+
+impl From<diesel::result::Error> for AppError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => AppError::NotFound("record".to_string()),
+            e => AppError::Query(e),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for AppError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        AppError::Pool(e.to_string())
+    }
+}
+
+impl From<CryptoError> for AppError {
+    fn from(e: CryptoError) -> Self {
+        AppError::Crypto(e.to_string())
+    }
+}
+
+impl From<MailError> for AppError {
+    fn from(e: MailError) -> Self {
+        AppError::Mail(e.to_string())
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AppError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::BTreeMap, fs};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jwt::{
+    AlgorithmType, SignWithStore, SigningAlgorithm, Store, VerifyWithStore, VerifyingAlgorithm,
+};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    pkcs8::DecodePrivateKey,
+    signature::{SignatureEncoding, Signer, Verifier},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+const MIN_KEY_BITS: usize = 2048;
+
+// one RS256 key pair, the public half is published in the jwks
+pub struct JwtKey {
+    signing: SigningKey<Sha256>,
+    verifying: VerifyingKey<Sha256>,
+    public: RsaPublicKey,
+}
+
+impl SigningAlgorithm for JwtKey {
+    fn algorithm_type(&self) -> AlgorithmType {
+        AlgorithmType::Rs256
+    }
+
+    fn sign(&self, header: &str, claims: &str) -> Result<String, jwt::Error> {
+        let signature = self.signing.sign(format!("{header}.{claims}").as_bytes());
+        Ok(URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+}
+
+impl VerifyingAlgorithm for JwtKey {
+    fn algorithm_type(&self) -> AlgorithmType {
+        AlgorithmType::Rs256
+    }
+
+    fn verify_bytes(
+        &self,
+        header: &str,
+        claims: &str,
+        signature: &[u8],
+    ) -> Result<bool, jwt::Error> {
+        let Ok(signature) = Signature::try_from(signature) else {
+            return Ok(false);
+        };
+        let message = format!("{header}.{claims}");
+        Ok(self
+            .verifying
+            .verify(message.as_bytes(), &signature)
+            .is_ok())
+    }
+}
+
+// signs tokens with one key and puts its version in the `kid` header, so
+// other services can check them against /.well-known/jwks.json without
+// sharing a secret. like `SecretCipher`, older keys stay around to verify the
+// tokens they signed until those have expired. keys newer than the signing one
+// are only published, so verifiers have them cached before they are used
+pub struct JwtKeys {
+    keys: BTreeMap<u32, JwtKey>,
+    current: u32,
+}
+
+impl Store for JwtKeys {
+    type Algorithm = JwtKey;
+
+    fn get(&self, key_id: &str) -> Option<&JwtKey> {
+        key_id
+            .parse()
+            .ok()
+            .and_then(|version| self.keys.get(&version))
+    }
+}
+
+impl JwtKeys {
+    // `keys` is a comma separated list of `<version>:<path to a PEM RSA
+    // private key>`. `signing` is the version that signs, 0 for the highest
+    pub fn from_keys(keys: &str, signing: u32) -> Result<Self, String> {
+        let mut parsed = BTreeMap::new();
+        for entry in keys.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (version, path) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected <version>:<path>, got {entry}"))?;
+            let version: u32 = version
+                .parse()
+                .map_err(|_| format!("key version {version} is not a number"))?;
+            let pem = fs::read_to_string(path)
+                .map_err(|e| format!("couldn't read key {version} from {path}: {e}"))?;
+            parsed.insert(
+                version,
+                Self::parse_key(&pem).map_err(|e| format!("key {version}: {e}"))?,
+            );
+        }
+
+        let current = match signing {
+            0 => *parsed.keys().next_back().ok_or("no keys given")?,
+            version if parsed.contains_key(&version) => version,
+            version => return Err(format!("the signing key {version} is not one of the keys")),
+        };
+        Ok(JwtKeys {
+            keys: parsed,
+            current,
+        })
+    }
+
+    // PKCS#8 (`openssl genpkey`) or PKCS#1 (`openssl genrsa -traditional`)
+    fn parse_key(pem: &str) -> Result<JwtKey, String> {
+        let private = RsaPrivateKey::from_pkcs8_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+            .map_err(|_| "not a PEM encoded RSA private key".to_string())?;
+        if private.size() * 8 < MIN_KEY_BITS {
+            return Err(format!("must be at least {MIN_KEY_BITS} bits"));
+        }
+
+        let public = private.to_public_key();
+        Ok(JwtKey {
+            signing: SigningKey::new(private),
+            verifying: VerifyingKey::new(public.clone()),
+            public,
+        })
+    }
+
+    pub fn sign(&self, claims: impl Serialize) -> String {
+        (self.current.to_string().as_str(), claims)
+            .sign_with_store(self)
+            .unwrap()
+    }
+
+    // the key is picked by the token's `kid`, tokens without one (like the
+    // HMAC signed ones from before) or with an unknown one are rejected
+    pub fn verify<C: DeserializeOwned>(&self, token: &str) -> Result<C, jwt::Error> {
+        token.verify_with_store(self)
+    }
+
+    // every key that can still verify, newest first
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self
+            .keys
+            .iter()
+            .rev()
+            .map(|(version, key)| {
+                json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "kid": version.to_string(),
+                    "n": URL_SAFE_NO_PAD.encode(key.public.n().to_bytes_be()),
+                    "e": URL_SAFE_NO_PAD.encode(key.public.e().to_bytes_be()),
+                })
+            })
+            .collect();
+        json!({ "keys": keys })
+    }
+}
@@ -0,0 +1,141 @@
+// drives many logins at once through the real handlers and checks that a cheap
+// request made in the meantime isn't stuck behind them, which is what happens
+// when hashing or queries run on the worker thread. needs the database from
+// DATABASE_URL with the migrations applied:
+// cargo test --release -- --ignored health_latency
+use std::{
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{Service, ServiceResponse},
+    rt, test,
+    web::Data,
+    App,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    crypto::SecretCipher,
+    health_check_handler,
+    jwt_keys::JwtKeys,
+    mailer::{InMemoryMailer, Mailer},
+    password::{HashPool, PasswordScheme},
+    password_policy::PasswordPolicy,
+    service::{login_user_handler, register_user_handler},
+    settings::Settings,
+    store::{store_from_settings, UserStore},
+};
+
+const CONCURRENT_LOGINS: usize = 64;
+const PROBES: usize = 20;
+// a single argon2 verify already takes longer than this
+const MAX_PROBE_LATENCY: Duration = Duration::from_millis(50);
+
+async fn timed<S, R>(app: &S, req: R) -> (ServiceResponse, Duration)
+where
+    S: Service<R, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let start = Instant::now();
+    let resp = test::call_service(app, req).await;
+    (resp, start.elapsed())
+}
+
+fn percentile(latencies: &mut [Duration], p: usize) -> Duration {
+    latencies.sort();
+    latencies[(latencies.len() - 1) * p / 100]
+}
+
+#[actix_web::test]
+#[ignore = "needs a postgres database, see DATABASE_URL"]
+async fn health_latency_stays_flat_under_concurrent_logins() {
+    dotenv::dotenv().ok();
+    let settings = Settings {
+        jwt_keys: "1:testdata/jwt-test-1.pem".to_string(),
+        hash_secret: "load-test".to_string(),
+        database_url: std::env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+        ..Settings::default()
+    };
+
+    let cipher = SecretCipher::from_keys(&format!("1:{}", "00".repeat(32))).unwrap();
+    let jwt_keys = JwtKeys::from_keys(&settings.jwt_keys, 0).unwrap();
+    let mailer: Data<dyn Mailer> =
+        Data::from(Arc::new(InMemoryMailer::default()) as Arc<dyn Mailer>);
+    let store: Data<dyn UserStore> = Data::from(store_from_settings(&settings).unwrap());
+    let app = Rc::new(
+        test::init_service(
+            App::new()
+                .app_data(store.clone())
+                .app_data(Data::new(HashPool::new(
+                    4,
+                    PasswordScheme::new(&settings.hash_secret, &settings.password_hash).unwrap(),
+                )))
+                .app_data(Data::new(
+                    PasswordPolicy::new(&settings.password_policy).unwrap(),
+                ))
+                .app_data(Data::new(settings))
+                .app_data(Data::new(cipher))
+                .app_data(Data::new(jwt_keys))
+                .app_data(mailer)
+                .service(health_check_handler)
+                .service(register_user_handler)
+                .service(login_user_handler),
+        )
+        .await,
+    );
+
+    let credentials = json!({
+        "name": "load test",
+        "email": format!("{}@load.test", Uuid::new_v4()),
+        "password": "correct horse battery staple",
+    });
+    let req = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(&credentials)
+        .to_request();
+    assert!(test::call_service(&*app, req).await.status().is_success());
+    // skips the verification mail, login is what's being measured
+    let email = credentials["email"].as_str().unwrap().to_owned();
+    assert!(store.verify_email(&email).unwrap().is_some());
+
+    let logins: Vec<_> = (0..CONCURRENT_LOGINS)
+        .map(|_| {
+            let app = app.clone();
+            let req = test::TestRequest::post()
+                .uri("/auth/login")
+                .set_json(&credentials)
+                .to_request();
+            rt::spawn(async move {
+                let (resp, latency) = timed(&*app, req).await;
+                assert!(resp.status().is_success());
+                latency
+            })
+        })
+        .collect();
+
+    let mut probes = Vec::with_capacity(PROBES);
+    for _ in 0..PROBES {
+        let req = test::TestRequest::get().uri("/api/health").to_request();
+        let (resp, latency) = timed(&*app, req).await;
+        assert!(resp.status().is_success());
+        probes.push(latency);
+        rt::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut login_latencies = Vec::with_capacity(CONCURRENT_LOGINS);
+    for login in logins {
+        login_latencies.push(login.await.unwrap());
+    }
+
+    let probe_p99 = percentile(&mut probes, 99);
+    assert!(
+        probe_p99 < MAX_PROBE_LATENCY,
+        "health p50 {:?} p99 {probe_p99:?}, login p50 {:?} p99 {:?}",
+        percentile(&mut probes, 50),
+        percentile(&mut login_latencies, 50),
+        percentile(&mut login_latencies, 99),
+    );
+}
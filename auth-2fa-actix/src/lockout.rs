@@ -0,0 +1,193 @@
+use actix_web::{web, HttpRequest};
+use chrono::{Duration, Utc};
+
+use crate::{
+    audit::{AuditAction, Outcome},
+    error::AppError,
+    model::AuditEvent,
+    session::ClientInfo,
+    store::{run, UserStore},
+};
+
+pub const LOGIN_SCOPE: &str = "login";
+pub const OTP_SCOPE: &str = "otp";
+// every mail sent on request counts here, see `AttemptKeys::record_sent`
+pub const MAIL_SCOPE: &str = "mail";
+// failures older than this no longer count towards a lockout
+const FAILURE_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+pub struct LockoutPolicy {
+    // failures allowed before the first lockout
+    pub free_failures: i32,
+    pub base_lockout_secs: i64,
+    pub max_lockout_secs: i64,
+}
+
+pub const ACCOUNT_LOCKOUT: LockoutPolicy = LockoutPolicy {
+    free_failures: 5,
+    base_lockout_secs: 30,
+    max_lockout_secs: 60 * 60,
+};
+
+// looser than the account one since many users can share an address
+pub const IP_LOCKOUT: LockoutPolicy = LockoutPolicy {
+    free_failures: 20,
+    base_lockout_secs: 30,
+    max_lockout_secs: 60 * 60,
+};
+
+// a few mails go out straight away, then each one waits longer so the routes
+// that send them can't be used to flood an inbox or keep a code fresh
+pub const ACCOUNT_MAIL_BUDGET: LockoutPolicy = LockoutPolicy {
+    free_failures: 3,
+    base_lockout_secs: 60,
+    max_lockout_secs: 60 * 60,
+};
+
+pub const IP_MAIL_BUDGET: LockoutPolicy = LockoutPolicy {
+    free_failures: 20,
+    base_lockout_secs: 60,
+    max_lockout_secs: 60 * 60,
+};
+
+impl LockoutPolicy {
+    // the lockout doubles with every failure past the free ones
+    pub fn lockout_for(&self, failures: i32) -> Option<Duration> {
+        if failures <= self.free_failures {
+            return None;
+        }
+        let exponent = (failures - self.free_failures - 1).min(20) as u32;
+        let secs = self
+            .base_lockout_secs
+            .saturating_mul(1 << exponent)
+            .min(self.max_lockout_secs);
+        Some(Duration::seconds(secs))
+    }
+}
+
+pub fn account_key(scope: &str, user_id: &str) -> String {
+    format!("{scope}:user:{user_id}")
+}
+
+// the counters a single attempt is tracked under, the account one is left
+// out when the user is unknown unless `or_unknown_account` is used
+#[derive(Clone)]
+pub struct AttemptKeys {
+    scope: String,
+    user_id: Option<String>,
+    account: Option<String>,
+    ip: Option<String>,
+    client: ClientInfo,
+}
+
+impl AttemptKeys {
+    // the ip is the one `ClientInfo` sees, behind a proxy that takes
+    // trusted_proxies to be set or every client shares the proxy's counter
+    pub fn new(scope: &str, user_id: Option<&str>, req: &HttpRequest) -> Self {
+        let client = ClientInfo::from_request(req);
+        AttemptKeys {
+            scope: scope.to_owned(),
+            user_id: user_id.map(str::to_owned),
+            account: user_id.map(|id| account_key(scope, id)),
+            ip: client.ip.as_ref().map(|ip| format!("{scope}:ip:{ip}")),
+            client,
+        }
+    }
+
+    // unknown emails get an account counter of their own, so they lock out
+    // like real accounts and the 429 doesn't tell the two apart
+    pub fn or_unknown_account(mut self, email: &str) -> Self {
+        if self.account.is_none() {
+            self.account = Some(format!("{}:unknown:{}", self.scope, email.to_lowercase()));
+        }
+        self
+    }
+
+    fn keys(&self) -> Vec<(&str, &LockoutPolicy)> {
+        let (account_policy, ip_policy) = match self.scope.as_str() {
+            MAIL_SCOPE => (&ACCOUNT_MAIL_BUDGET, &IP_MAIL_BUDGET),
+            _ => (&ACCOUNT_LOCKOUT, &IP_LOCKOUT),
+        };
+        let mut keys = Vec::new();
+        if let Some(account) = &self.account {
+            keys.push((account.as_str(), account_policy));
+        }
+        if let Some(ip) = &self.ip {
+            keys.push((ip.as_str(), ip_policy));
+        }
+        keys
+    }
+
+    // fails with TooManyRequests while any of the keys is locked
+    pub async fn check(&self, data: &web::Data<dyn UserStore>) -> Result<(), AppError> {
+        let keys = self.clone();
+        run(data, move |db| {
+            let now = Utc::now().naive_utc();
+            let mut retry_after = None;
+            for (key, _) in keys.keys() {
+                if let Some(locked_until) = db.get_login_attempt(key)?.and_then(|a| a.locked_until)
+                {
+                    if locked_until > now {
+                        let secs = (locked_until - now).num_seconds().max(1);
+                        retry_after = Some(retry_after.map_or(secs, |r: i64| r.max(secs)));
+                    }
+                }
+            }
+            match retry_after {
+                Some(retry_after) => Err(AppError::TooManyRequests { retry_after }),
+                None => Ok(()),
+            }
+        })
+        .await
+    }
+
+    // a failure that locks one of the keys also goes into the audit log
+    pub async fn record_failure(&self, data: &web::Data<dyn UserStore>) -> Result<(), AppError> {
+        let keys = self.clone();
+        run(data, move |db| {
+            let now = Utc::now().naive_utc();
+            let reset_before = now - Duration::seconds(FAILURE_WINDOW_SECS);
+            let mut locked_until = None;
+            for (key, policy) in keys.keys() {
+                let attempt = db.record_failed_attempt(key, reset_before, &|failures| {
+                    policy.lockout_for(failures)
+                })?;
+                if let Some(until) = attempt.locked_until.filter(|until| *until > now) {
+                    locked_until = locked_until.max(Some(until));
+                }
+            }
+
+            if let Some(until) = locked_until {
+                let event = AuditEvent::new(
+                    AuditAction::Lockout,
+                    Outcome::Success,
+                    keys.user_id.as_deref(),
+                    &keys.client,
+                )
+                .detail(format!("{} locked until {until}", keys.scope));
+                db.add_audit_event(event)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    // counts a mail against the budget like a failure, nothing clears it but
+    // the failure window running out
+    pub async fn record_sent(&self, data: &web::Data<dyn UserStore>) -> Result<(), AppError> {
+        self.record_failure(data).await
+    }
+
+    // only the account counter is cleared, otherwise an attacker could reset
+    // the ip counter by logging into an account of their own
+    pub async fn record_success(&self, data: &web::Data<dyn UserStore>) -> Result<(), AppError> {
+        let account = self.account.clone();
+        run(data, move |db| {
+            if let Some(account) = &account {
+                db.clear_login_attempt(account)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
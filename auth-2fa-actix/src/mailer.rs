@@ -0,0 +1,133 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+use serde::Serialize;
+
+use crate::settings::{MailSettings, MailTransport};
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not send mail: {}", self.0)
+    }
+}
+
+impl std::error::Error for MailError {}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: Mail) -> Result<(), MailError>;
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, username: &str, password: &str, from: &str) -> Result<Self, MailError> {
+        let transport = SmtpTransport::relay(host)
+            .map_err(|e| MailError(e.to_string()))?
+            .credentials(Credentials::new(username.to_owned(), password.to_owned()))
+            .build();
+        let from = from
+            .parse()
+            .map_err(|_| MailError(format!("invalid from address {from}")))?;
+        Ok(SmtpMailer { transport, from })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: Mail) -> Result<(), MailError> {
+        let to: Mailbox = mail
+            .to
+            .parse()
+            .map_err(|_| MailError(format!("invalid address {}", mail.to)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|e| MailError(e.to_string()))?;
+        self.transport
+            .send(&message)
+            .map_err(|e| MailError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+// appends every mail as a json line, handy for local development
+pub struct FileMailer {
+    path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileMailer { path: path.into() }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: Mail) -> Result<(), MailError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| MailError(e.to_string()))?;
+        let line = serde_json::to_string(&mail).map_err(|e| MailError(e.to_string()))?;
+        writeln!(file, "{line}").map_err(|e| MailError(e.to_string()))
+    }
+}
+
+// keeps sent mails around so tests can read them back
+#[derive(Default)]
+pub struct InMemoryMailer {
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl InMemoryMailer {
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for InMemoryMailer {
+    fn send(&self, mail: Mail) -> Result<(), MailError> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
+
+// see `MailSettings`, the smtp settings are checked by `Settings::validate`
+pub fn mailer_from_settings(mail: &MailSettings) -> Arc<dyn Mailer> {
+    match mail.transport {
+        MailTransport::Smtp => Arc::new(
+            SmtpMailer::new(
+                &mail.smtp_host,
+                &mail.smtp_username,
+                &mail.smtp_password,
+                &mail.from,
+            )
+            .expect("checked by validate"),
+        ),
+        MailTransport::File => Arc::new(FileMailer::new(&mail.file)),
+        MailTransport::Memory => Arc::new(InMemoryMailer::default()),
+    }
+}
@@ -1,9 +1,35 @@
+pub mod audit;
+pub mod crypto;
 pub mod db;
+pub mod email_otp;
+pub mod email_verification;
+pub mod error;
+pub mod jwt_keys;
+pub mod lockout;
+pub mod mailer;
 pub mod middleware;
+pub mod migrations;
 pub mod model;
+pub mod otp;
+pub mod password;
+pub mod password_policy;
+pub mod password_reset;
+pub mod qr;
+pub mod recovery;
+pub mod refresh_token;
 pub mod response;
 pub mod schema;
 pub mod service;
+pub mod session;
+pub mod settings;
+pub mod store;
+
+#[cfg(test)]
+mod api_tests;
+#[cfg(test)]
+mod load_test;
+
+use std::{io, sync::Arc};
 
 use actix_cors::Cors;
 use actix_web::{
@@ -17,12 +43,30 @@ use actix_web_httpauth::middleware::HttpAuthentication;
 use serde_json::json;
 
 use crate::{
+    crypto::SecretCipher,
     db::Database,
-    middleware::auth_middleware::validator,
+    jwt_keys::JwtKeys,
+    mailer::{mailer_from_settings, Mailer},
+    middleware::{auth_middleware::validator, require_role::RequireRole},
+    model::{ADMIN_ROLE, ROLES},
+    otp::{Clock, SystemClock},
+    password::{HashPool, PasswordScheme},
+    password_policy::PasswordPolicy,
     service::{
-        disable_otp_handler, generate_otp_handler, login_user_handler, register_user_handler,
-        validate_otp_handler, verify_otp_handler,
+        admin_audit_log_handler, admin_disable_2fa_handler, admin_export_audit_log_handler,
+        admin_list_users_handler, admin_lock_user_handler, admin_unlock_user_handler,
+        audit_log_handler, change_email_handler, change_password_handler, default_factor_handler,
+        delete_account_handler, disable_email_otp_handler, disable_otp_handler,
+        enable_email_otp_handler, export_account_handler, export_audit_log_handler,
+        forgot_password_handler, generate_otp_handler, jwks_handler, list_sessions_handler,
+        login_user_handler, logout_handler, otp_qr_code_handler, recovery_codes_status_handler,
+        refresh_token_handler, regenerate_recovery_codes_handler, register_user_handler,
+        resend_verification_handler, reset_password_handler, revoke_all_sessions_handler,
+        revoke_session_handler, send_email_otp_handler, step_up_handler, validate_otp_handler,
+        verify_email_handler, verify_otp_handler,
     },
+    settings::Settings,
+    store::{store_from_settings, UserStore},
 };
 
 #[get("/api/health")]
@@ -31,6 +75,102 @@ async fn health_check_handler() -> impl Responder {
     HttpResponse::Ok().json(json!({"status":"success", "message": MESSAGE}))
 }
 
+// every route, shared with the tests so they run against the same app
+fn routes(cfg: &mut web::ServiceConfig) {
+    let bearer_middleware = HttpAuthentication::bearer(validator);
+
+    cfg.service(health_check_handler)
+        .service(jwks_handler)
+        .service(login_user_handler)
+        .service(register_user_handler)
+        .service(refresh_token_handler)
+        .service(logout_handler)
+        .service(forgot_password_handler)
+        .service(reset_password_handler)
+        .service(validate_otp_handler)
+        .service(send_email_otp_handler)
+        .service(verify_email_handler)
+        .service(resend_verification_handler)
+        // before the catch-all scope below, which would otherwise take /admin
+        .service(
+            web::scope("/admin")
+                .wrap(RequireRole(ADMIN_ROLE))
+                .wrap(bearer_middleware.clone())
+                .service(admin_list_users_handler)
+                .service(admin_disable_2fa_handler)
+                .service(admin_lock_user_handler)
+                .service(admin_unlock_user_handler)
+                .service(admin_audit_log_handler)
+                .service(admin_export_audit_log_handler),
+        )
+        .service(
+            web::scope("")
+                .wrap(bearer_middleware)
+                .service(generate_otp_handler)
+                .service(otp_qr_code_handler)
+                .service(verify_otp_handler)
+                .service(disable_otp_handler)
+                .service(enable_email_otp_handler)
+                .service(disable_email_otp_handler)
+                .service(default_factor_handler)
+                .service(recovery_codes_status_handler)
+                .service(regenerate_recovery_codes_handler)
+                .service(list_sessions_handler)
+                .service(revoke_session_handler)
+                .service(revoke_all_sessions_handler)
+                .service(step_up_handler)
+                .service(audit_log_handler)
+                .service(export_audit_log_handler)
+                .service(change_password_handler)
+                .service(change_email_handler)
+                .service(delete_account_handler)
+                .service(export_account_handler),
+        );
+}
+
+// the commands below report bad settings like the server does
+fn database_url() -> String {
+    match Settings::load_database_url() {
+        Ok(database_url) => database_url,
+        Err(e) => {
+            eprint!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// `migrate` applies pending migrations and exits
+fn migrate() -> std::io::Result<()> {
+    let database_url = database_url();
+    let applied = Database::new(&database_url)
+        .run_migrations()
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    println!("applied {} migration(s)", applied.len());
+    for version in applied {
+        println!("  {version}");
+    }
+    Ok(())
+}
+
+// `set-role <email> <role>`, also how the first admin is made
+fn set_role(email: &str, role: &str) -> io::Result<()> {
+    if !ROLES.contains(&role) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown role {role}, expected one of {}", ROLES.join(", ")),
+        ));
+    }
+
+    let database_url = database_url();
+    let db = Database::new(&database_url);
+    let user = db
+        .get_user_by_email(email)
+        .and_then(|user| db.set_role(&user.id, role))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    println!("{} is now {}", user.email, user.role);
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     if std::env::var_os("RUST_LOG").is_none() {
@@ -38,13 +178,63 @@ async fn main() -> std::io::Result<()> {
     }
 
     env_logger::init();
+    dotenv::dotenv().ok();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["migrate"] => migrate(),
+            ["set-role", email, role] => set_role(email, role),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "usage: auth-2fa-actix [migrate | set-role <email> <role>]",
+            )),
+        };
+    }
+
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprint!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    // built once here so every worker shares the same connection pool
+    let store: Data<dyn UserStore> = match store_from_settings(&settings) {
+        Ok(store) => Data::from(store),
+        Err(e) => {
+            eprintln!("refusing to start: {e}");
+            std::process::exit(1);
+        }
+    };
+    let cipher = Data::new(
+        SecretCipher::from_keys(&settings.otp_encryption_keys).expect("checked by validate"),
+    );
+    let jwt_keys = Data::new(
+        JwtKeys::from_keys(&settings.jwt_keys, settings.jwt_signing_key)
+            .expect("checked by validate"),
+    );
+    let hasher = Data::new(HashPool::new(
+        settings.hash_threads,
+        PasswordScheme::new(&settings.hash_secret, &settings.password_hash)
+            .expect("checked by validate"),
+    ));
+    let policy =
+        Data::new(PasswordPolicy::new(&settings.password_policy).expect("checked by validate"));
+    let mailer: Data<dyn Mailer> = Data::from(mailer_from_settings(&settings.mail));
+    let clock: Data<dyn Clock> = Data::from(Arc::new(SystemClock) as Arc<dyn Clock>);
+
+    let listen_addr = settings.listen_addr.to_owned();
+    let settings = Data::new(settings);
 
     println!("Server started successfully");
 
     HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
-            .allowed_origin("http://localhost:3000/")
+        let cors = settings
+            .cors_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
             .allowed_methods(vec!["GET", "POST"])
             .allowed_headers(vec![
                 header::CONTENT_TYPE,
@@ -53,25 +243,20 @@ async fn main() -> std::io::Result<()> {
             ])
             .supports_credentials();
 
-        let bearer_middleware = HttpAuthentication::bearer(validator);
-
         App::new()
-            .app_data(Data::new(Database::new()))
-            .service(health_check_handler)
-            .service(login_user_handler)
-            .service(register_user_handler)
-            .service(
-                web::scope("")
-                    .wrap(bearer_middleware)
-                    .service(generate_otp_handler)
-                    .service(verify_otp_handler)
-                    .service(validate_otp_handler)
-                    .service(disable_otp_handler),
-            )
+            .app_data(store.clone())
+            .app_data(cipher.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(hasher.clone())
+            .app_data(policy.clone())
+            .app_data(mailer.clone())
+            .app_data(clock.clone())
+            .app_data(settings.clone())
+            .configure(routes)
             .wrap(cors)
             .wrap(Logger::default())
     })
-    .bind(("127.0.0.1", 8080))?
+    .bind(listen_addr)?
     .run()
     .await?;
 
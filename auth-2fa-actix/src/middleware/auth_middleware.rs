@@ -1,29 +1,203 @@
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
+use actix_web::{dev::ServiceRequest, web, Error, HttpMessage};
 use actix_web_httpauth::extractors::{
     bearer::{self, BearerAuth},
     AuthenticationError,
 };
-use hmac::{Hmac, Mac};
-use jwt::VerifyWithKey;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    jwt_keys::JwtKeys,
+    model::{Session, User},
+    session::{is_stale, methods, PASSWORD_METHOD},
+    settings::TokenSettings,
+    store::{run, UserStore},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    Session,
+    PreAuth,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TokenClaims {
     pub(crate) id: String,
+    pub(crate) scope: TokenScope,
+    pub(crate) role: String,
+    // the session a session token belongs to, pre-auth tokens have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sid: Option<String>,
+    // how and when the user last proved who they are, see `Session::amr`.
+    // sensitive routes ask for a recent totp, see `service::require_step_up`
+    #[serde(default)]
+    pub(crate) amr: Vec<String>,
+    #[serde(default)]
+    pub(crate) auth_time: i64,
+    pub(crate) exp: i64,
+    pub(crate) iat: i64,
+    pub(crate) jti: String,
 }
 
+impl TokenClaims {
+    fn new(
+        user: &User,
+        scope: TokenScope,
+        sid: Option<&str>,
+        amr: Vec<String>,
+        auth_time: i64,
+        ttl_secs: i64,
+    ) -> Self {
+        let now = Utc::now().timestamp();
+        TokenClaims {
+            id: user.id.to_owned(),
+            scope,
+            role: user.role.to_owned(),
+            sid: sid.map(str::to_owned),
+            amr,
+            auth_time,
+            exp: now + ttl_secs,
+            iat: now,
+            jti: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub fn session(user: &User, session: &Session, settings: &TokenSettings) -> Self {
+        Self::new(
+            user,
+            TokenScope::Session,
+            Some(&session.id),
+            methods(session),
+            session.auth_time.and_utc().timestamp(),
+            settings.access_token_ttl_secs,
+        )
+    }
+
+    pub fn pre_auth(user: &User, settings: &TokenSettings) -> Self {
+        Self::new(
+            user,
+            TokenScope::PreAuth,
+            None,
+            vec![PASSWORD_METHOD.to_string()],
+            Utc::now().timestamp(),
+            settings.pre_auth_token_ttl_secs,
+        )
+    }
+
+    fn is_expired(&self) -> bool {
+        Utc::now().timestamp() >= self.exp
+    }
+}
+
+pub fn sign_token(keys: &JwtKeys, claims: TokenClaims) -> String {
+    keys.sign(claims)
+}
+
+// accepts only full session tokens, used for every protected route
 pub async fn validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let jwt_secret: String = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set!");
-    let key: Hmac<Sha256> = Hmac::new_from_slice(jwt_secret.as_bytes()).unwrap();
+    authenticate(req, credentials, &[TokenScope::Session]).await
+}
+
+// also accepts pre-auth tokens, only used for the otp validate route
+pub async fn pre_auth_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    authenticate(
+        req,
+        credentials,
+        &[TokenScope::Session, TokenScope::PreAuth],
+    )
+    .await
+}
+
+// a valid signature isn't enough, the user and session the token was issued
+// for have to still allow it
+fn check_current(
+    claims: TokenClaims,
+    user: &User,
+    session: Option<&Session>,
+) -> Result<TokenClaims, &'static str> {
+    // a password reset ends every session issued before it
+    if let Some(valid_after) = user.sessions_valid_after {
+        if claims.iat < valid_after.and_utc().timestamp() {
+            return Err("Token revoked");
+        }
+    }
+    if user.locked_at.is_some() {
+        return Err("Account locked");
+    }
+    // a role change takes effect right away, not when the token runs out
+    if user.role != claims.role {
+        return Err("Role changed");
+    }
+    // pre-auth tokens come before the session does
+    if claims.scope == TokenScope::Session {
+        match session {
+            Some(s) if s.user_id == user.id && s.revoked_at.is_none() => {}
+            _ => return Err("Session revoked"),
+        }
+    }
+    Ok(claims)
+}
+
+async fn authenticate(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+    allowed_scopes: &[TokenScope],
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
     let token_string = credentials.token();
 
-    let claims: Result<TokenClaims, &str> = token_string
-        .verify_with_key(&key)
-        .map_err(|_| "Invalid token");
+    let claims: Result<TokenClaims, &str> = req
+        .app_data::<web::Data<JwtKeys>>()
+        .ok_or("Keys missing")
+        .and_then(|keys| keys.verify(token_string).map_err(|_| "Invalid token"))
+        .and_then(|claims: TokenClaims| {
+            if claims.is_expired() {
+                Err("Token expired")
+            } else if !allowed_scopes.contains(&claims.scope) {
+                Err("Token scope not allowed")
+            } else {
+                Ok(claims)
+            }
+        });
+
+    let claims = match (claims, req.app_data::<web::Data<dyn UserStore>>().cloned()) {
+        (Ok(claims), Some(data)) => {
+            let (user_id, session_id) = (claims.id.to_owned(), claims.sid.to_owned());
+            let loaded = run(&data, move |db| {
+                let user = db.get_user_by_userid(&user_id)?;
+                let session = match session_id {
+                    Some(session_id) => db.get_session(&session_id)?,
+                    None => None,
+                };
+
+                // keeps last_seen_at roughly current for the session list
+                let now = Utc::now().naive_utc();
+                if let Some(session) = session
+                    .as_ref()
+                    .filter(|s| s.revoked_at.is_none() && is_stale(s, now))
+                {
+                    db.touch_session(&session.id, now)?;
+                }
+                Ok((user, session))
+            })
+            .await;
+            match loaded {
+                Ok((user, session)) => check_current(claims, &user, session.as_ref()),
+                Err(AppError::NotFound(_)) => Err("Unknown user"),
+                // a database outage shouldn't look like every token went bad
+                Err(e) => return Err((e.into(), req)),
+            }
+        }
+        (claims, _) => claims,
+    };
 
     match claims {
         Ok(value) => {
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+
+use diesel::{
+    migration::{self, MigrationSource},
+    pg::Pg,
+    PgConnection,
+};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+// everything under migrations/ is compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+// applied migrations this build doesn't ship, a non-empty list means the
+// database was migrated by a newer version of the code
+fn unknown_migrations(conn: &mut PgConnection) -> migration::Result<Vec<String>> {
+    let known: HashSet<String> = MigrationSource::<Pg>::migrations(&MIGRATIONS)?
+        .iter()
+        .map(|m| m.name().version().to_string())
+        .collect();
+
+    Ok(conn
+        .applied_migrations()?
+        .iter()
+        .map(|v| v.to_string())
+        .filter(|v| !known.contains(v))
+        .collect())
+}
+
+fn check_not_newer(conn: &mut PgConnection) -> migration::Result<()> {
+    let unknown = unknown_migrations(conn)?;
+    if !unknown.is_empty() {
+        return Err(format!(
+            "the database has migrations this build doesn't know about ({}), it was migrated by a newer version",
+            unknown.join(", ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// applies whatever is pending, returns the versions that were applied
+pub fn run_migrations(conn: &mut PgConnection) -> migration::Result<Vec<String>> {
+    check_not_newer(conn)?;
+    Ok(conn
+        .run_pending_migrations(MIGRATIONS)?
+        .iter()
+        .map(|v| v.to_string())
+        .collect())
+}
+
+// for when migrations are left to the `migrate` command, fails unless the
+// schema matches this build exactly
+pub fn check_migrations(conn: &mut PgConnection) -> migration::Result<()> {
+    check_not_newer(conn)?;
+    let pending = conn.pending_migrations(MIGRATIONS)?;
+    if !pending.is_empty() {
+        let versions: Vec<String> = pending.iter().map(|m| m.name().to_string()).collect();
+        return Err(format!(
+            "the database is missing migrations ({}), run the `migrate` command first",
+            versions.join(", ")
+        )
+        .into());
+    }
+    Ok(())
+}
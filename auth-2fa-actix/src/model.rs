@@ -11,11 +11,164 @@ pub struct User {
 
     pub otp_enabled: bool,
     pub otp_verified: bool,
+    // encrypted, see `crypto::SecretCipher`
+    #[serde(skip_serializing)]
     pub otp_base32: Option<String>,
     pub otp_auth_url: Option<String>,
 
     pub created_at: Option<chrono::NaiveDateTime>,
     pub updated_at: Option<chrono::NaiveDateTime>,
+
+    // last totp time step that was accepted, codes at or before it are replays
+    pub otp_last_used_step: Option<i64>,
+
+    pub email_otp_enabled: bool,
+    // `totp` or `email`, see `SecondFactor`
+    pub default_factor: String,
+
+    // tokens issued before this are rejected, bumped on password reset
+    pub sessions_valid_after: Option<chrono::NaiveDateTime>,
+
+    // one of `ROLES`, carried in the jwt
+    pub role: String,
+    // set by an admin, a locked account can't log in
+    pub locked_at: Option<chrono::NaiveDateTime>,
+    // login and 2FA enrollment wait for the address to be confirmed, see
+    // `email_verification`
+    pub email_verified: bool,
+
+    // `otp::TotpParams` of the stored secret, all unset for secrets from
+    // before they were stored
+    pub otp_algorithm: Option<String>,
+    pub otp_digits: Option<i32>,
+    pub otp_period: Option<i32>,
+}
+
+pub const USER_ROLE: &str = "user";
+pub const ADMIN_ROLE: &str = "admin";
+pub const ROLES: &[&str] = &[USER_ROLE, ADMIN_ROLE];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecondFactor {
+    Totp,
+    Email,
+}
+
+impl SecondFactor {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecondFactor::Totp => "totp",
+            SecondFactor::Email => "email",
+        }
+    }
+}
+
+impl User {
+    // the factor asked for at login, the chosen default if it is still
+    // enabled, otherwise whichever one is
+    pub fn second_factor(&self) -> Option<SecondFactor> {
+        let enabled = |factor: &SecondFactor| match factor {
+            SecondFactor::Totp => self.otp_enabled,
+            SecondFactor::Email => self.email_otp_enabled,
+        };
+        let default = match self.default_factor.as_str() {
+            "email" => SecondFactor::Email,
+            _ => SecondFactor::Totp,
+        };
+
+        [default, SecondFactor::Totp, SecondFactor::Email]
+            .into_iter()
+            .find(enabled)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::recovery_codes )]
+pub struct RecoveryCode {
+    pub id: String,
+    pub user_id: String,
+    pub code_hash: String,
+    pub used_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::refresh_tokens )]
+pub struct RefreshToken {
+    pub id: String,
+    pub user_id: String,
+    // every token rotated out of the same login shares a family
+    pub family_id: String,
+    pub token_hash: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+// one per login, its id is also the family of the refresh tokens issued in it
+// and the `sid` of its access tokens
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::sessions )]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    // comma separated methods the user proved themselves with in this
+    // session, see `session::PASSWORD_METHOD`. carried in the jwt `amr`
+    pub amr: String,
+    // when they last did, at login or with a step-up
+    pub auth_time: chrono::NaiveDateTime,
+}
+
+// an entry of the audit log, written once and never changed, see `audit`
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::audit_events )]
+pub struct AuditEvent {
+    pub id: String,
+    // none when the account is unknown, e.g. a login with an unregistered email
+    pub user_id: Option<String>,
+    // see `audit::AuditAction`
+    pub action: String,
+    pub outcome: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::login_attempts, treat_none_as_null = true)]
+pub struct LoginAttempt {
+    // `<scope>:user:<id>` or `<scope>:ip:<addr>`
+    pub attempt_key: String,
+    pub failures: i32,
+    pub locked_until: Option<chrono::NaiveDateTime>,
+    pub last_failure_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::email_otps )]
+pub struct EmailOtp {
+    pub user_id: String,
+    pub code_hash: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::password_resets )]
+pub struct PasswordReset {
+    pub id: String,
+    pub user_id: String,
+    pub token_hash: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub used_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,12 +184,107 @@ pub struct UserLoginSchema {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum QrCodeFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
 #[derive(Debug, Deserialize)]
+pub struct QrCodeSchema {
+    #[serde(default)]
+    pub format: QrCodeFormat,
+}
+
+// the totp profile to enroll with, see `otp::TotpParams::negotiate`
+#[derive(Debug, Deserialize, Default)]
 pub struct GenerateOTPSchema {
-    pub email: String,
+    pub algorithm: Option<String>,
+    pub digits: Option<usize>,
+    pub period: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct VerifyOTPSchema {
     pub token: String,
 }
+
+// a totp token, one of the recovery codes handed out on verify or a code
+// sent by email
+#[derive(Debug, Deserialize)]
+pub struct ValidateOTPSchema {
+    pub token: Option<String>,
+    pub recovery_code: Option<String>,
+    pub email_code: Option<String>,
+}
+
+// a totp token when 2FA is enabled, the password otherwise
+#[derive(Debug, Deserialize)]
+pub struct StepUpSchema {
+    pub token: Option<String>,
+    // instead of the token, for someone who lost their authenticator
+    pub recovery_code: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordSchema {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeEmailSchema {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DefaultFactorSchema {
+    pub factor: SecondFactor,
+}
+
+// offset/limit paging for the list routes
+#[derive(Debug, Deserialize)]
+pub struct PageSchema {
+    #[serde(default)]
+    pub offset: i64,
+    pub limit: Option<i64>,
+}
+
+impl PageSchema {
+    // (offset, limit), 50 per page unless asked for, at most 100
+    pub fn bounds(&self) -> (i64, i64) {
+        (self.offset.max(0), self.limit.unwrap_or(50).clamp(1, 100))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenSchema {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordSchema {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailSchema {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendVerificationSchema {
+    pub email: String,
+}
+
+// 2FA users also need a totp token or a recovery code
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordSchema {
+    pub token: String,
+    pub password: String,
+    pub otp_token: Option<String>,
+    pub recovery_code: Option<String>,
+}
@@ -0,0 +1,183 @@
+use constant_time_eq::constant_time_eq;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    error::{AppError, FieldError},
+    model::{GenerateOTPSchema, User},
+    settings::TotpSettings,
+};
+
+pub const ALGORITHMS: &[&str] = &["SHA1", "SHA256", "SHA512"];
+pub const PERIODS: &[u64] = &[30, 60];
+
+// everything but unreserved characters and '@', so a space, '&', '#' or '+'
+// in the issuer or email can't break the uri
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'@');
+
+// how a user's codes are generated, picked when the secret is generated and
+// stored next to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpParams {
+    pub algorithm: String,
+    pub digits: usize,
+    pub period: u64,
+}
+
+impl TotpParams {
+    // what every secret used before the params were stored, fixed rather than
+    // read from the settings so changing those doesn't break old secrets
+    pub fn legacy() -> Self {
+        TotpParams {
+            algorithm: "SHA1".to_string(),
+            digits: 6,
+            period: 30,
+        }
+    }
+
+    // what new secrets get unless the client asks for something else
+    pub fn defaults(settings: &TotpSettings) -> Self {
+        TotpParams {
+            algorithm: "SHA1".to_string(),
+            digits: settings.digits,
+            period: settings.step_secs,
+        }
+    }
+
+    pub fn of(user: &User) -> Self {
+        match (&user.otp_algorithm, user.otp_digits, user.otp_period) {
+            (Some(algorithm), Some(digits), Some(period)) => TotpParams {
+                algorithm: algorithm.clone(),
+                digits: digits as usize,
+                period: period as u64,
+            },
+            _ => Self::legacy(),
+        }
+    }
+
+    // what the client asked for at enrollment, anything left out is the
+    // default. authenticator apps only agree on a few values so only those
+    // are accepted
+    pub fn negotiate(
+        settings: &TotpSettings,
+        requested: &GenerateOTPSchema,
+    ) -> Result<Self, AppError> {
+        let defaults = Self::defaults(settings);
+        let mut errors = Vec::new();
+        let mut error = |field, message: String| {
+            errors.push(FieldError {
+                field,
+                code: "unsupported",
+                message,
+            })
+        };
+
+        let algorithm = match &requested.algorithm {
+            Some(algorithm) => algorithm.to_uppercase(),
+            None => defaults.algorithm,
+        };
+        if !ALGORITHMS.contains(&algorithm.as_str()) {
+            error(
+                "algorithm",
+                format!("must be one of {}", ALGORITHMS.join(", ")),
+            );
+        }
+        let digits = requested.digits.unwrap_or(defaults.digits);
+        if !(6..=8).contains(&digits) {
+            error("digits", "must be between 6 and 8".to_string());
+        }
+        let period = requested.period.unwrap_or(defaults.period);
+        if requested.period.is_some() && !PERIODS.contains(&period) {
+            error("period", "must be 30 or 60 seconds".to_string());
+        }
+
+        match errors.is_empty() {
+            true => Ok(TotpParams {
+                algorithm,
+                digits,
+                period,
+            }),
+            false => Err(AppError::InvalidFields(errors)),
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match self.algorithm.as_str() {
+            "SHA256" => Algorithm::SHA256,
+            "SHA512" => Algorithm::SHA512,
+            _ => Algorithm::SHA1,
+        }
+    }
+}
+
+pub fn build_totp(
+    settings: &TotpSettings,
+    params: &TotpParams,
+    otp_base32: &str,
+) -> Result<TOTP, AppError> {
+    let secret = Secret::Encoded(otp_base32.to_owned())
+        .to_bytes()
+        .map_err(|e| AppError::Crypto(format!("otp secret is not valid base32: {e:?}")))?;
+    TOTP::new(
+        params.algorithm(),
+        params.digits,
+        settings.skew,
+        params.period,
+        secret,
+    )
+    .map_err(|e| AppError::Crypto(e.to_string()))
+}
+
+// provisioning uri for authenticator apps, carries the plaintext secret
+pub fn otp_auth_url(
+    settings: &TotpSettings,
+    params: &TotpParams,
+    email: &str,
+    otp_base32: &str,
+) -> String {
+    let issuer = utf8_percent_encode(&settings.issuer, URI_COMPONENT);
+    let email = utf8_percent_encode(email, URI_COMPONENT);
+    let TotpParams {
+        algorithm,
+        digits,
+        period,
+    } = params;
+    format!(
+        "otpauth://totp/{issuer}:{email}?secret={otp_base32}&issuer={issuer}\
+         &algorithm={algorithm}&digits={digits}&period={period}"
+    )
+}
+
+// like `TOTP::check` but returns the time step the token matched so it can be
+// recorded and not accepted again
+pub fn matching_step(totp: &TOTP, token: &str, time: u64) -> Option<u64> {
+    let current = time / totp.step;
+    let skew = totp.skew as u64;
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| constant_time_eq(totp.generate(step * totp.step).as_bytes(), token.as_bytes()))
+}
+
+// where the handlers read the time for totp checks from, so tests can control it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        current_time()
+    }
+}
+
+pub fn current_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,180 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use tokio::sync::oneshot;
+
+use crate::{error::AppError, settings::PasswordHashSettings};
+
+// how passwords are hashed. hashes are PHC strings
+// (`$argon2id$v=19$m=...,t=...,p=...$<salt>$<hash>`) that carry their own
+// algorithm and parameters, so hashes made with older settings keep
+// verifying after the settings change and are upgraded on the next login
+pub struct PasswordScheme {
+    // mixed into every hash, never stored with it
+    secret: Vec<u8>,
+    algorithm: Algorithm,
+    params: Params,
+}
+
+impl PasswordScheme {
+    pub fn new(hash_secret: &str, settings: &PasswordHashSettings) -> Result<Self, String> {
+        let algorithm = settings
+            .algorithm
+            .parse()
+            .map_err(|_| format!("unknown algorithm {}", settings.algorithm))?;
+        let params = Params::new(
+            settings.memory_kib,
+            settings.iterations,
+            settings.parallelism,
+            None,
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(PasswordScheme {
+            secret: hash_secret.as_bytes().to_vec(),
+            algorithm,
+            params,
+        })
+    }
+
+    fn argon2(&self) -> Result<Argon2<'_>, AppError> {
+        Argon2::new_with_secret(
+            &self.secret,
+            self.algorithm,
+            Version::V0x13,
+            self.params.clone(),
+        )
+        .map_err(|e| AppError::Crypto(e.to_string()))
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, AppError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()?
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| AppError::Crypto(e.to_string()))
+    }
+
+    // uses the algorithm and parameters stored in the hash, not the current ones
+    pub fn verify(&self, hash: &str, password: &str) -> Result<bool, AppError> {
+        let parsed = PasswordHash::new(hash).map_err(|e| AppError::Crypto(e.to_string()))?;
+        match parsed.algorithm.as_str() {
+            "argon2id" | "argon2i" | "argon2d" => Ok(self
+                .argon2()?
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()),
+            other => Err(AppError::Crypto(format!(
+                "unsupported password hash algorithm {other}"
+            ))),
+        }
+    }
+
+    // true when the hash was made with another algorithm or version, or with
+    // less memory or fewer iterations than currently configured
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let Ok(params) = Params::try_from(&parsed) else {
+            return true;
+        };
+        parsed.algorithm != self.algorithm.ident()
+            || parsed.version != Some(Version::V0x13.into())
+            || params.m_cost() < self.params.m_cost()
+            || params.t_cost() < self.params.t_cost()
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// argon2 is slow on purpose, so hashing gets its own fixed set of threads.
+// at most `threads` hashes run at once and the rest queue up, a burst of
+// logins can't take over the cpu or the blocking pool the queries run on
+pub struct HashPool {
+    jobs: mpsc::Sender<Job>,
+    scheme: Arc<PasswordScheme>,
+    // made on first use, see `verify_dummy`
+    dummy_hash: Arc<OnceLock<String>>,
+}
+
+impl HashPool {
+    pub fn new(threads: usize, scheme: PasswordScheme) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads.max(1) {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("password-hash-{i}"))
+                .spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        // a panicking job shouldn't take the thread down with it
+                        Ok(job) => _ = panic::catch_unwind(AssertUnwindSafe(job)),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to start password hashing thread");
+        }
+        HashPool {
+            jobs,
+            scheme: Arc::new(scheme),
+            dummy_hash: Arc::new(OnceLock::new()),
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move || {
+                let _ = tx.send(f());
+            }))
+            .map_err(|_| AppError::Internal("password hashing pool is gone".to_string()))?;
+        rx.await
+            .map_err(|_| AppError::Internal("password hashing job panicked".to_string()))?
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, AppError> {
+        let (scheme, password) = (self.scheme.clone(), password.to_owned());
+        self.run(move || scheme.hash(&password)).await
+    }
+
+    pub async fn verify(&self, hash: &str, password: &str) -> Result<bool, AppError> {
+        let scheme = self.scheme.clone();
+        let (hash, password) = (hash.to_owned(), password.to_owned());
+        self.run(move || scheme.verify(&hash, &password)).await
+    }
+
+    // checks `password` against a hash nobody has, for logins with an unknown
+    // email. takes as long as a real check so the response time doesn't give
+    // away which emails are registered
+    pub async fn verify_dummy(&self, password: &str) -> Result<(), AppError> {
+        let (scheme, dummy_hash) = (self.scheme.clone(), self.dummy_hash.clone());
+        let password = password.to_owned();
+        self.run(move || {
+            let hash = match dummy_hash.get() {
+                Some(hash) => hash,
+                None => {
+                    let hash = scheme.hash("")?;
+                    dummy_hash.get_or_init(|| hash)
+                }
+            };
+            scheme.verify(hash, &password).map(|_| ())
+        })
+        .await
+    }
+
+    // cheap, doesn't go through the pool
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        self.scheme.needs_rehash(hash)
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use actix_web::web;
+use regex::{Regex, RegexBuilder};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    error::{AppError, FieldError},
+    settings::PasswordPolicySettings,
+};
+
+// parts of the user's details shorter than this aren't worth rejecting
+const MIN_PERSONAL_LEN: usize = 3;
+
+// what new passwords have to satisfy on registration, reset and change.
+// existing passwords aren't checked again, login keeps working for them
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    banned: Vec<Regex>,
+    breached: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    pub fn new(settings: &PasswordPolicySettings) -> Result<Self, String> {
+        let banned = settings
+            .banned_patterns
+            .iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("banned pattern {pattern:?} is invalid: {e}"))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let breached = match settings.breached_passwords_file.as_str() {
+            "" => None,
+            path => {
+                File::open(path).map_err(|e| format!("couldn't open {path}: {e}"))?;
+                Some(PathBuf::from(path))
+            }
+        };
+
+        Ok(PasswordPolicy {
+            min_length: settings.min_length,
+            max_length: settings.max_length,
+            banned,
+            breached,
+        })
+    }
+
+    // every rule `password` breaks, reported for `field`. `personal` are the
+    // user's own details (email, name) the password shouldn't contain
+    pub async fn check(
+        &self,
+        field: &'static str,
+        password: &str,
+        personal: &[&str],
+    ) -> Result<(), AppError> {
+        let mut errors = Vec::new();
+        let mut error = |code, message: String| {
+            errors.push(FieldError {
+                field,
+                code,
+                message,
+            })
+        };
+
+        let length = password.chars().count();
+        if length < self.min_length {
+            error(
+                "too_short",
+                format!("must be at least {} characters", self.min_length),
+            );
+        }
+        if length > self.max_length {
+            error(
+                "too_long",
+                format!("must be at most {} characters", self.max_length),
+            );
+        }
+        if self.banned.iter().any(|re| re.is_match(password)) {
+            error(
+                "banned_pattern",
+                "contains a common word or pattern".to_string(),
+            );
+        }
+        let lowercase = password.to_lowercase();
+        let contains_personal = personal
+            .iter()
+            .flat_map(|detail| detail.split(['@', ' ']))
+            .filter(|part| part.chars().count() >= MIN_PERSONAL_LEN)
+            .any(|part| lowercase.contains(&part.to_lowercase()));
+        if contains_personal {
+            error(
+                "personal_info",
+                "can't contain your name or email address".to_string(),
+            );
+        }
+
+        // overly long ones are rejected anyway, no need to hash them
+        if let (Some(path), true) = (&self.breached, length <= self.max_length) {
+            let (path, hash) = (path.clone(), sha1_hex(password));
+            let breached = web::block(move || is_listed(&path, &hash))
+                .await?
+                .map_err(|e| AppError::Internal(format!("breached password list: {e}")))?;
+            if breached {
+                error(
+                    "breached",
+                    "has appeared in a data breach, pick another one".to_string(),
+                );
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(AppError::InvalidFields(errors)),
+        }
+    }
+}
+
+fn sha1_hex(password: &str) -> String {
+    hex::encode_upper(Sha1::digest(password.as_bytes()))
+}
+
+// the list is a text file of `<uppercase sha1>:<count>` lines sorted by hash,
+// like the "ordered by hash" download of Have I Been Pwned. it can be tens of
+// gigabytes, so it is binary searched on disk instead of loaded
+fn is_listed(path: &Path, hash: &str) -> io::Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut low, mut high) = (0, reader.seek(SeekFrom::End(0))?);
+    // a matching line, if any, starts somewhere in low..high
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let (start, line) = line_from(&mut reader, middle)?;
+        let Some(line) = line else {
+            high = middle;
+            continue;
+        };
+
+        let listed = line.split(':').next().unwrap_or_default().trim();
+        match listed.cmp(hash) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = start + line.len() as u64,
+            // no line starts between middle and start
+            Ordering::Greater => high = middle,
+        }
+    }
+    Ok(false)
+}
+
+// the first line starting at or after `offset`, with where it starts
+fn line_from(reader: &mut BufReader<File>, offset: u64) -> io::Result<(u64, Option<String>)> {
+    let mut start = offset;
+    if offset > 0 {
+        // the line ending just before `offset` (if any) belongs to the
+        // previous line
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        let mut skipped = Vec::new();
+        start += reader.read_until(b'\n', &mut skipped)? as u64 - 1;
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+
+    let mut line = String::new();
+    match reader.read_line(&mut line)? {
+        0 => Ok((start, None)),
+        _ => Ok((start, Some(line))),
+    }
+}
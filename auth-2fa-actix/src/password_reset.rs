@@ -0,0 +1,55 @@
+use chrono::{Duration, Utc};
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::{
+    crypto::keyed_hash,
+    error::AppError,
+    mailer::{Mail, Mailer},
+    model::{PasswordReset, User},
+    settings::Settings,
+    store::UserStore,
+};
+
+// the token is random and only its keyed hash is stored, so it can't be
+// forged or recovered from the db
+pub fn send_password_reset(
+    data: &dyn UserStore,
+    mailer: &dyn Mailer,
+    settings: &Settings,
+    user: &User,
+) -> Result<(), AppError> {
+    let data_byte: [u8; 32] = rand::thread_rng().gen();
+    let token = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &data_byte);
+    let ttl_secs = settings.tokens.password_reset_ttl_secs;
+    let date_time = Utc::now().naive_utc();
+    data.add_password_reset(PasswordReset {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.id.to_owned(),
+        token_hash: keyed_hash(&settings.hash_secret, &token),
+        expires_at: date_time + Duration::seconds(ttl_secs),
+        used_at: None,
+        created_at: date_time,
+    })?;
+
+    mailer.send(Mail {
+        to: user.email.to_owned(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Reset your password at {}?token={token}\nThe link expires in {} minutes. If you didn't ask for this, ignore this mail.",
+            settings.password_reset_url,
+            ttl_secs / 60
+        ),
+    })?;
+    Ok(())
+}
+
+// the reset for the token if it is unused and not expired
+pub fn find_password_reset(
+    data: &dyn UserStore,
+    settings: &Settings,
+    token: &str,
+) -> Result<Option<PasswordReset>, AppError> {
+    let reset = data.get_password_reset_by_hash(&keyed_hash(&settings.hash_secret, token))?;
+    Ok(reset.filter(|r| r.used_at.is_none() && r.expires_at > Utc::now().naive_utc()))
+}
@@ -0,0 +1,41 @@
+use chrono::{Duration, Utc};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{model::RefreshToken, settings::TokenSettings};
+
+// opaque random token, only its hash is stored
+pub fn generate_refresh_token() -> String {
+    let mut rng = rand::thread_rng();
+    let data_byte: [u8; 32] = rng.gen();
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &data_byte)
+}
+
+pub fn hash_refresh_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// builds the db record for a new token, every token of a login shares the
+// session id as its family
+pub fn new_refresh_token(
+    settings: &TokenSettings,
+    user_id: &str,
+    session_id: &str,
+) -> (String, RefreshToken) {
+    let token = generate_refresh_token();
+    let date_time = Utc::now().naive_utc();
+    let record = RefreshToken {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_owned(),
+        family_id: session_id.to_owned(),
+        token_hash: hash_refresh_token(&token),
+        expires_at: date_time + Duration::days(settings.refresh_token_ttl_days),
+        revoked_at: None,
+        created_at: date_time,
+    };
+    (token, record)
+}
@@ -1,6 +1,6 @@
 use serde::Serialize;
 
-use crate::model::User;
+use crate::model::{AuditEvent, Session, User};
 
 #[derive(Serialize)]
 pub struct GenericResponse {
@@ -8,19 +8,33 @@ pub struct GenericResponse {
     pub message: String,
 }
 
+#[derive(Serialize, Debug)]
+pub struct OtpEnrollmentResponse {
+    pub status: String,
+    pub secret: String,
+    pub otp_auth_url: String,
+    pub algorithm: String,
+    pub digits: usize,
+    pub period: u64,
+}
+
 #[derive(Serialize, Debug)]
 pub struct UserData {
     pub id: String,
     pub email: String,
+    pub email_verified: bool,
     pub name: String,
 
     pub otp_enabled: bool,
     pub otp_verified: bool,
-    pub otp_base32: Option<String>,
-    pub otp_auth_url: Option<String>,
+    pub email_otp_enabled: bool,
+    pub default_factor: String,
+    pub role: String,
+    pub locked: bool,
 
-    pub created_at: chrono::NaiveDateTime,
-    pub updated_at: chrono::NaiveDateTime,
+    // nullable in the table, null instead of a panic for rows without them
+    pub created_at: Option<chrono::NaiveDateTime>,
+    pub updated_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Serialize, Debug)]
@@ -29,16 +43,80 @@ pub struct UserResponse {
     pub user: UserData,
 }
 
+#[derive(Serialize, Debug)]
+pub struct SessionData {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+    // the session the request was made with
+    pub current: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditEventData {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub action: String,
+    pub outcome: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+// everything stored about the user, leaving out the password hash, the otp
+// secret and the hashes of codes and tokens
+#[derive(Serialize, Debug)]
+pub struct AccountExport {
+    pub exported_at: chrono::NaiveDateTime,
+    pub user: UserData,
+    pub recovery_codes_remaining: i64,
+    pub sessions: Vec<SessionData>,
+    pub audit_log: Vec<AuditEventData>,
+}
+
+pub fn audit_event_to_response(event: &AuditEvent) -> AuditEventData {
+    AuditEventData {
+        id: event.id.to_owned(),
+        user_id: event.user_id.to_owned(),
+        action: event.action.to_owned(),
+        outcome: event.outcome.to_owned(),
+        ip: event.ip.to_owned(),
+        user_agent: event.user_agent.to_owned(),
+        detail: event.detail.to_owned(),
+        created_at: event.created_at,
+    }
+}
+
+pub fn session_to_response(session: &Session, current_id: Option<&str>) -> SessionData {
+    SessionData {
+        id: session.id.to_owned(),
+        user_agent: session.user_agent.to_owned(),
+        ip: session.ip.to_owned(),
+        created_at: session.created_at,
+        last_seen_at: session.last_seen_at,
+        current: current_id == Some(session.id.as_str()),
+        revoked_at: session.revoked_at,
+    }
+}
+
 pub fn user_to_response(user: &User) -> UserData {
     UserData {
         id: user.id.to_owned(),
         name: user.name.to_owned(),
         email: user.email.to_owned(),
+        email_verified: user.email_verified,
         otp_enabled: user.otp_enabled.to_owned(),
         otp_verified: user.otp_verified.to_owned(),
-        otp_base32: user.otp_base32.to_owned(),
-        otp_auth_url: user.otp_auth_url.to_owned(),
-        created_at: user.created_at.unwrap(),
-        updated_at: user.updated_at.unwrap(),
+        email_otp_enabled: user.email_otp_enabled,
+        default_factor: user.default_factor.to_owned(),
+        role: user.role.to_owned(),
+        locked: user.locked_at.is_some(),
+        created_at: user.created_at,
+        updated_at: user.updated_at,
     }
 }
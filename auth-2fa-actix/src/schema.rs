@@ -1,5 +1,105 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_events (id) {
+        #[max_length = 255]
+        id -> Varchar,
+        #[max_length = 255]
+        user_id -> Nullable<Varchar>,
+        #[max_length = 32]
+        action -> Varchar,
+        #[max_length = 16]
+        outcome -> Varchar,
+        #[max_length = 64]
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Text>,
+        detail -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    email_otps (user_id) {
+        #[max_length = 255]
+        user_id -> Varchar,
+        #[max_length = 255]
+        code_hash -> Varchar,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_attempts (attempt_key) {
+        #[max_length = 255]
+        attempt_key -> Varchar,
+        failures -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        last_failure_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    password_resets (id) {
+        #[max_length = 255]
+        id -> Varchar,
+        #[max_length = 255]
+        user_id -> Varchar,
+        #[max_length = 255]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        #[max_length = 255]
+        id -> Varchar,
+        #[max_length = 255]
+        user_id -> Varchar,
+        #[max_length = 255]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        #[max_length = 255]
+        id -> Varchar,
+        #[max_length = 255]
+        user_id -> Varchar,
+        #[max_length = 255]
+        family_id -> Varchar,
+        #[max_length = 255]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        #[max_length = 255]
+        id -> Varchar,
+        #[max_length = 255]
+        user_id -> Varchar,
+        user_agent -> Nullable<Text>,
+        #[max_length = 64]
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        #[max_length = 64]
+        amr -> Varchar,
+        auth_time -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (id) {
         #[max_length = 255]
@@ -18,5 +118,36 @@ diesel::table! {
         otp_auth_url -> Nullable<Varchar>,
         created_at -> Nullable<Timestamp>,
         updated_at -> Nullable<Timestamp>,
+        otp_last_used_step -> Nullable<Int8>,
+        email_otp_enabled -> Bool,
+        #[max_length = 16]
+        default_factor -> Varchar,
+        sessions_valid_after -> Nullable<Timestamp>,
+        #[max_length = 32]
+        role -> Varchar,
+        locked_at -> Nullable<Timestamp>,
+        email_verified -> Bool,
+        #[max_length = 8]
+        otp_algorithm -> Nullable<Varchar>,
+        otp_digits -> Nullable<Int4>,
+        otp_period -> Nullable<Int4>,
     }
 }
+
+diesel::joinable!(audit_events -> users (user_id));
+diesel::joinable!(email_otps -> users (user_id));
+diesel::joinable!(password_resets -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_events,
+    email_otps,
+    login_attempts,
+    password_resets,
+    recovery_codes,
+    refresh_tokens,
+    sessions,
+    users,
+);
@@ -1,318 +1,1597 @@
 use actix_web::{
+    get,
+    http::header,
     post,
     web::{self, ReqData},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse,
 };
-use argonautica::{Hasher, Verifier};
+use actix_web_httpauth::middleware::HttpAuthentication;
 use chrono::Utc;
-use hmac::{Hmac, Mac};
-use jwt::SignWithKey;
 use rand::Rng;
 use serde_json::json;
-use sha2::Sha256;
-use totp_rs::{Secret, TOTP};
 use uuid::Uuid;
 
 use crate::{
-    db::Database,
-    middleware::auth_middleware::TokenClaims,
-    model::{GenerateOTPSchema, User, UserLoginSchema, UserRegisterSchema, VerifyOTPSchema},
-    response::{user_to_response, GenericResponse},
+    audit::{record, AuditAction, Outcome},
+    crypto::SecretCipher,
+    email_otp::{check_email_otp, send_email_otp},
+    email_verification::{check_verification_token, send_email_verification},
+    error::AppError,
+    jwt_keys::JwtKeys,
+    lockout::{account_key, AttemptKeys, LOGIN_SCOPE, MAIL_SCOPE, OTP_SCOPE},
+    mailer::{Mail, Mailer},
+    middleware::auth_middleware::{pre_auth_validator, sign_token, TokenClaims},
+    model::{
+        AuditEvent, ChangeEmailSchema, ChangePasswordSchema, DefaultFactorSchema,
+        ForgotPasswordSchema, GenerateOTPSchema, PageSchema, QrCodeFormat, QrCodeSchema,
+        RecoveryCode, RefreshTokenSchema, ResendVerificationSchema, ResetPasswordSchema,
+        SecondFactor, StepUpSchema, User, UserLoginSchema, UserRegisterSchema, ValidateOTPSchema,
+        VerifyEmailSchema, VerifyOTPSchema, USER_ROLE,
+    },
+    otp::{build_totp, matching_step, otp_auth_url, Clock, TotpParams},
+    password::HashPool,
+    password_policy::PasswordPolicy,
+    password_reset::{find_password_reset, send_password_reset},
+    qr,
+    recovery::{generate_recovery_codes, hash_recovery_code},
+    refresh_token::{hash_refresh_token, new_refresh_token},
+    response::{
+        audit_event_to_response, session_to_response, user_to_response, AccountExport,
+        AuditEventData, GenericResponse, OtpEnrollmentResponse, SessionData, UserData,
+    },
+    session::{
+        new_session, with_method, ClientInfo, EMAIL_METHOD, PASSWORD_METHOD, RECOVERY_METHOD,
+        TOTP_METHOD,
+    },
+    settings::{Settings, TokenSettings, TotpSettings},
+    store::{run, UserStore},
 };
 
+// the public keys access tokens are signed with, for other services to verify
+// them. a key stays listed while tokens signed with it can still be valid
+#[get("/.well-known/jwks.json")]
+async fn jwks_handler(keys: web::Data<JwtKeys>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(keys.jwks())
+}
+
 // register user
 #[post("/auth/register")]
 async fn register_user_handler(
-    data: web::Data<Database>,
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    hasher: web::Data<HashPool>,
+    policy: web::Data<PasswordPolicy>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
     body: web::Json<UserRegisterSchema>,
-) -> impl Responder {
-    let exiting_user = data.if_user_exists(&body.email);
-    match exiting_user {
-        true => {
-            let resp = GenericResponse {
-                status: "fail".to_string(),
-                message: format!("user with email {} already exists", &body.email),
-            };
-            return HttpResponse::Conflict().json(resp);
-        }
-        false => {
-            let hash_secret = std::env::var("HASH_SECRET").expect("HASH_SECRET must be set");
-            let mut hasher = Hasher::default();
-            let hash = hasher
-                .with_password(body.password.to_owned())
-                .with_secret_key(hash_secret)
-                .hash()
-                .unwrap();
-
-            let uuid = Uuid::new_v4();
-            let date_time = Utc::now().naive_utc();
-
-            let user = User {
-                id: uuid.to_string(),
-                email: body.email.to_owned(),
-                name: body.name.to_owned(),
-                password: hash,
-                otp_enabled: false,
-                otp_verified: false,
-                otp_base32: None,
-                otp_auth_url: None,
-                created_at: Some(date_time),
-                updated_at: Some(date_time),
-            };
-
-            let final_result: Result<usize, diesel::result::Error> = data.add_user(user);
-            match final_result {
-                Ok(_) => HttpResponse::Ok()
-                    .json(json!({"status": "success", "message": "registration successful"})),
-                Err(e) => {
-                    return HttpResponse::Conflict().json(
-                        json!({"status": "fail", "message": "could not register", "error": e.to_string()}),
-                    );
-                }
-            }
-        }
+) -> Result<HttpResponse, AppError> {
+    if body.email.parse::<lettre::Address>().is_err() {
+        return Err(AppError::Validation(
+            "email is not a valid address".to_string(),
+        ));
+    }
+    policy
+        .check("password", &body.password, &[&body.email, &body.name])
+        .await?;
+
+    let client = ClientInfo::from_request(&req);
+    let entered_email = body.email.to_owned();
+    if run(&data, move |db| db.if_user_exists(&entered_email)).await? {
+        let event = AuditEvent::new(AuditAction::Register, Outcome::Failure, None, &client)
+            .detail("email already registered");
+        record(&data, event).await?;
+        return Err(AppError::Conflict(format!(
+            "user with email {} already exists",
+            &body.email
+        )));
     }
+
+    let hash = hasher.hash(&body.password).await?;
+
+    let uuid = Uuid::new_v4();
+    let date_time = Utc::now().naive_utc();
+
+    let user = User {
+        id: uuid.to_string(),
+        email: body.email.to_owned(),
+        name: body.name.to_owned(),
+        password: hash,
+        otp_enabled: false,
+        otp_verified: false,
+        otp_base32: None,
+        otp_auth_url: None,
+        created_at: Some(date_time),
+        updated_at: Some(date_time),
+        otp_last_used_step: None,
+        email_otp_enabled: false,
+        default_factor: SecondFactor::Totp.as_str().to_string(),
+        sessions_valid_after: None,
+        role: USER_ROLE.to_string(),
+        locked_at: None,
+        email_verified: false,
+        otp_algorithm: None,
+        otp_digits: None,
+        otp_period: None,
+    };
+
+    run(&data, move |db| {
+        let event = AuditEvent::new(
+            AuditAction::Register,
+            Outcome::Success,
+            Some(&user.id),
+            &client,
+        );
+        db.add_user(user.clone())?;
+        db.add_audit_event(event)?;
+        send_email_verification(mailer.as_ref(), &settings, &user)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "registration successful, check your email for the verification link"})))
+}
+
+// the link from the registration mail, verifying the address unlocks login
+#[post("/auth/email/verify")]
+async fn verify_email_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    body: web::Json<VerifyEmailSchema>,
+) -> Result<HttpResponse, AppError> {
+    let invalid_link =
+        || AppError::Unauthorized("verification link is invalid or expired".to_string());
+
+    let email = check_verification_token(&settings, &body.token, Utc::now().timestamp())
+        .ok_or_else(invalid_link)?;
+    let client = ClientInfo::from_request(&req);
+    run(&data, move |db| {
+        let us = db.verify_email(&email)?.ok_or_else(invalid_link)?;
+        db.add_audit_event(AuditEvent::new(
+            AuditAction::EmailVerify,
+            Outcome::Success,
+            Some(&us.id),
+            &client,
+        ))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "email verified".to_string(),
+    }))
+}
+
+// answers the same way whether or not the address is registered, the mail
+// budget included
+#[post("/auth/email/verify/resend")]
+async fn resend_verification_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
+    body: web::Json<ResendVerificationSchema>,
+) -> Result<HttpResponse, AppError> {
+    let entered_email = body.into_inner().email;
+    let user = find_user_by_email(&data, &entered_email).await?;
+    let budget = AttemptKeys::new(MAIL_SCOPE, user.as_ref().map(|u| u.id.as_str()), &req)
+        .or_unknown_account(&entered_email);
+    budget.check(&data).await?;
+
+    if let Some(user) = user.filter(|user| !user.email_verified) {
+        run(&data, move |_| {
+            send_email_verification(mailer.as_ref(), &settings, &user)
+        })
+        .await?;
+    }
+    budget.record_sent(&data).await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "if the email is registered and not verified yet, a new link has been sent"
+            .to_string(),
+    }))
 }
 
 // login user
 #[post("/auth/login")]
 async fn login_user_handler(
-    data: web::Data<Database>,
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    hasher: web::Data<HashPool>,
+    mailer: web::Data<dyn Mailer>,
+    keys: web::Data<JwtKeys>,
+    settings: web::Data<Settings>,
     body: web::Json<UserLoginSchema>,
-) -> impl Responder {
-    let jwt_secret: Hmac<Sha256> = Hmac::new_from_slice(
-        std::env::var("JWT_SECRET")
-            .expect("JWT_SECRET must be set")
-            .as_bytes(),
-    )
-    .unwrap();
-
+) -> Result<HttpResponse, AppError> {
     let email = body.email.to_owned();
     let password = body.password.to_owned();
 
-    let user_from_db = data.get_user_by_email(&email);
-    match user_from_db {
-        Ok(user) => {
-            let hash_secret = std::env::var("HASH_SECRET").expect("HASH_SECRET must be set");
-            let mut verifier = Verifier::default();
-
-            let is_valid = tokio::spawn(async move {
-                let res: bool = verifier
-                    .with_hash(user.password)
-                    .with_password(password)
-                    .with_secret_key(hash_secret)
-                    .verify()
-                    .unwrap();
-                res
-            })
-            .await
-            .unwrap();
-
-            if is_valid {
-                let claims: TokenClaims = TokenClaims { id: user.id };
-                let token_str: String = claims.sign_with_key(&jwt_secret).unwrap();
-                HttpResponse::Ok().json(json!({"status": "pass".to_string(), "jwt_token": token_str, "2FA_enabled": user.otp_enabled}))
-            } else {
-                let resp = GenericResponse {
-                    status: "fail".to_string(),
-                    message: "incorrect username or password".to_string(),
-                };
-                HttpResponse::Unauthorized().json(json!(resp))
-            }
+    let user_from_db = match run(&data, move |db| db.get_user_by_email(&email)).await {
+        Ok(user) => Some(user),
+        Err(AppError::NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    let attempts = AttemptKeys::new(
+        LOGIN_SCOPE,
+        user_from_db.as_ref().map(|u| u.id.as_str()),
+        &req,
+    )
+    .or_unknown_account(&body.email);
+    attempts.check(&data).await?;
+
+    let client = ClientInfo::from_request(&req);
+    let login_event = |outcome, user_id: Option<&str>| {
+        AuditEvent::new(AuditAction::Login, outcome, user_id, &client)
+    };
+    // unknown emails get the same answer as a wrong password, after as long
+    let user = match user_from_db {
+        Some(user) => user,
+        None => {
+            hasher.verify_dummy(&password).await?;
+            record(
+                &data,
+                login_event(Outcome::Failure, None).detail("unknown email"),
+            )
+            .await?;
+            attempts.record_failure(&data).await?;
+            return Err(incorrect_credentials());
         }
-        Err(e) => {
-            let resp = GenericResponse {
-                status: "fail".to_string(),
-                message: format!("incorrect username or password {}", e.to_string()),
-            };
-            return HttpResponse::Conflict().json(resp);
+    };
+
+    let is_valid = hasher.verify(&user.password, &password).await?;
+    if !is_valid {
+        let event = login_event(Outcome::Failure, Some(&user.id)).detail("wrong password");
+        record(&data, event).await?;
+        attempts.record_failure(&data).await?;
+        return Err(incorrect_credentials());
+    }
+    attempts.record_success(&data).await?;
+    if hasher.needs_rehash(&user.password) {
+        upgrade_password_hash(&data, &hasher, &user, &password).await;
+    }
+    if user.locked_at.is_some() {
+        let event = login_event(Outcome::Failure, Some(&user.id)).detail("account locked");
+        record(&data, event).await?;
+        return Err(account_locked());
+    }
+    if !user.email_verified {
+        let event = login_event(Outcome::Failure, Some(&user.id)).detail("email not verified");
+        record(&data, event).await?;
+        return Err(email_not_verified());
+    }
+
+    // 2FA users only get a pre-auth token here, it is exchanged for
+    // a session token by /auth/otp/validate
+    if let Some(factor) = user.second_factor() {
+        let event = login_event(Outcome::Success, Some(&user.id))
+            .detail(format!("{} required", factor.as_str()));
+        record(&data, event).await?;
+        if factor == SecondFactor::Email {
+            let (user, settings) = (user.clone(), settings.clone());
+            run(&data, move |db| {
+                send_email_otp(db, mailer.as_ref(), &settings, &user)
+            })
+            .await?;
         }
+
+        let token_str: String = sign_token(&keys, TokenClaims::pre_auth(&user, &settings.tokens));
+        return Ok(HttpResponse::Ok().json(json!({"status": "pass".to_string(), "pre_auth_token": token_str, "2FA_enabled": true, "second_factor": factor})));
     }
+
+    let expires_in = settings.tokens.access_token_ttl_secs;
+    let event = login_event(Outcome::Success, Some(&user.id));
+    let (token_str, refresh_token) = run(&data, move |db| {
+        db.add_audit_event(event)?;
+        issue_session_tokens(db, &settings, &keys, &user, client, &[PASSWORD_METHOD])
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass".to_string(), "jwt_token": token_str, "refresh_token": refresh_token, "expires_in": expires_in, "2FA_enabled": false})))
 }
 
+// the only place the plaintext secret is handed out, it is stored encrypted
 #[post("/auth/otp/generate")]
 async fn generate_otp_handler(
-    data: web::Data<Database>,
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    cipher: web::Data<SecretCipher>,
+    settings: web::Data<Settings>,
     req_user: Option<ReqData<TokenClaims>>,
-    body: web::Json<GenerateOTPSchema>,
-) -> impl Responder {
-    match req_user {
-        Some(u) => {
-            let user_exists = data.if_user_exists_userid(&u.id);
-            match user_exists {
-                true => {
-                    let mut rng = rand::thread_rng();
-                    let data_byte: [u8; 21] = rng.gen();
-
-                    let base32_string =
-                        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &data_byte);
-                    let totp = TOTP::new(
-                        totp_rs::Algorithm::SHA1,
-                        6,
-                        1,
-                        30,
-                        Secret::Encoded(base32_string).to_bytes().unwrap(),
-                    )
-                    .unwrap();
-
-                    let otp_base32 = totp.get_secret_base32();
-                    let email = body.email.to_owned();
-                    let issuer = "anusikh";
-                    let otp_auth_url = format!(
-                        "otpauth://totp/{issuer}:{email}?secret={otp_base32}&issuer={issuer}"
-                    );
-
-                    let res = data.update_totp_for_user(&u.id, &otp_base32, &otp_auth_url, false);
-                    match res {
-                        Ok(_) => HttpResponse::Ok().json(json!(GenericResponse {
-                            status: "pass".to_string(),
-                            message: format!(
-                                "base32: {}, otp_auth_url: {}",
-                                otp_base32, otp_auth_url
-                            )
-                        })),
-                        Err(e) => HttpResponse::NotFound().json(json!(GenericResponse {
-                            status: "fail".to_string(),
-                            message: format!("something went wrong: {}", e.to_string())
-                        })),
-                    }
-                }
-                false => {
-                    let resp = GenericResponse {
-                        status: "fail".to_string(),
-                        message: format!("cannot find user {}", u.id),
-                    };
-                    HttpResponse::NotFound().json(resp)
-                }
-            }
+    body: Option<web::Json<GenerateOTPSchema>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let requested = body.map(web::Json::into_inner).unwrap_or_default();
+    let params = TotpParams::negotiate(&settings.totp, &requested)?;
+
+    let data_byte: [u8; 21] = rand::thread_rng().gen();
+    let base32_string = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &data_byte);
+    let totp = build_totp(&settings.totp, &params, &base32_string)?;
+    let otp_base32 = totp.get_secret_base32();
+
+    let encrypted = cipher.encrypt(&otp_base32);
+    let client = ClientInfo::from_request(&req);
+    let stored = params.clone();
+    let us = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        if !us.email_verified {
+            return Err(email_not_verified());
         }
-        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
-    }
+        // a new secret would turn the current one off, that goes through
+        // /auth/otp/disable and its step-up
+        if us.otp_enabled {
+            return Err(AppError::Conflict(
+                "2FA is already enabled, disable it first".to_string(),
+            ));
+        }
+
+        let us = db.update_totp_for_user(&u.id, Some((&encrypted, &stored)), false)?;
+        db.add_audit_event(AuditEvent::new(
+            AuditAction::OtpGenerate,
+            Outcome::Success,
+            Some(&us.id),
+            &client,
+        ))?;
+        Ok(us)
+    })
+    .await?;
+    let otp_auth_url = otp_auth_url(&settings.totp, &params, &us.email, &otp_base32);
+    Ok(HttpResponse::Ok().json(OtpEnrollmentResponse {
+        status: "pass".to_string(),
+        secret: otp_base32,
+        otp_auth_url,
+        algorithm: params.algorithm,
+        digits: params.digits,
+        period: params.period,
+    }))
+}
+
+// qr code of the provisioning uri, only while the secret is generated but
+// not verified yet since it carries the secret
+#[get("/auth/otp/qr")]
+async fn otp_qr_code_handler(
+    data: web::Data<dyn UserStore>,
+    cipher: web::Data<SecretCipher>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<QrCodeSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let cipher = cipher.into_inner();
+    let (us, otp_base32) = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        if us.otp_verified {
+            return Err(AppError::Forbidden("2FA is already verified".to_string()));
+        }
+
+        let otp_base32 = load_otp_secret(db, &cipher, &us)?;
+        Ok((us, otp_base32))
+    })
+    .await?;
+
+    let params = TotpParams::of(&us);
+    let otp_auth_url = otp_auth_url(&settings.totp, &params, &us.email, &otp_base32);
+    let (content_type, body) = match query.format {
+        QrCodeFormat::Png => ("image/png", qr::render_png(&otp_auth_url)),
+        QrCodeFormat::Svg => (
+            "image/svg+xml",
+            qr::render_svg(&otp_auth_url).map(String::into_bytes),
+        ),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(body.map_err(AppError::Internal)?))
 }
 
 #[post("/auth/otp/verify")]
 async fn verify_otp_handler(
-    data: web::Data<Database>,
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    cipher: web::Data<SecretCipher>,
+    clock: web::Data<dyn Clock>,
+    settings: web::Data<Settings>,
     req_user: Option<ReqData<TokenClaims>>,
     body: web::Json<VerifyOTPSchema>,
-) -> impl Responder {
-    match req_user {
-        Some(u) => {
-            let user = data.get_user_by_userid(&u.id);
-            match user {
-                Ok(us) => {
-                    let totp = TOTP::new(
-                        totp_rs::Algorithm::SHA1,
-                        6,
-                        1,
-                        30,
-                        Secret::Encoded(us.otp_base32.unwrap()).to_bytes().unwrap(),
-                    )
-                    .unwrap();
-
-                    let is_valid = totp.check_current(&body.token).unwrap();
-                    if !is_valid {
-                        let json_error = GenericResponse {
-                            status: "fail".to_string(),
-                            message: "Token is invalid or user doesn't exist".to_string(),
-                        };
-                        return HttpResponse::Forbidden().json(json_error);
-                    }
-
-                    let res = data.update_totp_for_user(&u.id, "", "", true);
-                    match res {
-                        Ok(usr) => HttpResponse::Ok().json(json!(
-                            {"status":"pass","otp_verified": true, "user": user_to_response(&usr)}
-                        )),
-                        Err(e) => HttpResponse::NotFound().json(GenericResponse {
-                            status: "fail".to_string(),
-                            message: format!("something went wrong {}", e.to_string()),
-                        }),
-                    }
-                }
-                Err(e) => HttpResponse::NotFound().json(GenericResponse {
-                    status: "fail".to_string(),
-                    message: format!("something went wrong {}", e.to_string()),
-                }),
-            }
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    // the same lockout as /auth/otp/validate, the code is as guessable here
+    let attempts = AttemptKeys::new(OTP_SCOPE, Some(&u.id), &req);
+    attempts.check(&data).await?;
+
+    let cipher = cipher.into_inner();
+    let now = clock.now();
+    let token = body.into_inner().token;
+    let client = ClientInfo::from_request(&req);
+    let result = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        // verifying again would hand out new recovery codes without step-up
+        if us.otp_verified {
+            return Err(AppError::Conflict("2FA is already verified".to_string()));
         }
-        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
-    }
+
+        let otp_base32 = load_otp_secret(db, &cipher, &us)?;
+        let is_valid = check_totp(db, &settings.totp, &us, &otp_base32, &token, now)?;
+        let outcome = if is_valid {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        };
+        db.add_audit_event(AuditEvent::new(
+            AuditAction::OtpVerify,
+            outcome,
+            Some(&us.id),
+            &client,
+        ))?;
+        if !is_valid {
+            return Ok(None);
+        }
+
+        let usr = db.update_totp_for_user(&u.id, None, true)?;
+        let codes = issue_recovery_codes(db, &settings, &usr.id)?;
+        Ok(Some((usr, codes)))
+    })
+    .await?;
+    let Some((usr, codes)) = result else {
+        attempts.record_failure(&data).await?;
+        return Err(invalid_code());
+    };
+    attempts.record_success(&data).await?;
+
+    Ok(HttpResponse::Ok().json(json!(
+        {"status":"pass","otp_verified": true, "user": user_to_response(&usr), "recovery_codes": codes}
+    )))
 }
 
-#[post("/auth/otp/validate")]
+// accepts the pre-auth token from login and exchanges it for a session token
+#[post(
+    "/auth/otp/validate",
+    wrap = "HttpAuthentication::bearer(pre_auth_validator)"
+)]
+#[allow(clippy::too_many_arguments)]
 async fn validate_otp_handler(
-    data: web::Data<Database>,
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    cipher: web::Data<SecretCipher>,
+    keys: web::Data<JwtKeys>,
+    clock: web::Data<dyn Clock>,
+    settings: web::Data<Settings>,
     req_user: Option<ReqData<TokenClaims>>,
-    body: web::Json<VerifyOTPSchema>,
-) -> impl Responder {
-    match req_user {
-        Some(u) => {
-            let user = data.get_user_by_userid(&u.id);
-            match user {
-                Ok(us) => {
-                    if !us.otp_enabled {
-                        let json_error = GenericResponse {
-                            status: "fail".to_string(),
-                            message: "2FA not enabled".to_string(),
-                        };
-
-                        return HttpResponse::Forbidden().json(json_error);
-                    }
-
-                    let totp = TOTP::new(
-                        totp_rs::Algorithm::SHA1,
-                        6,
-                        1,
-                        30,
-                        Secret::Encoded(us.otp_base32.unwrap()).to_bytes().unwrap(),
-                    )
-                    .unwrap();
-
-                    let is_valid = totp.check_current(&body.token).unwrap();
-                    if !is_valid {
-                        let json_error = GenericResponse {
-                            status: "fail".to_string(),
-                            message: "Token is invalid or user doesn't exist".to_string(),
-                        };
-                        return HttpResponse::Forbidden().json(json_error);
-                    }
-
-                    HttpResponse::Ok().json(GenericResponse {
-                        status: "pass".to_string(),
-                        message: "verified".to_string(),
-                    })
-                }
-                Err(e) => HttpResponse::NotFound().json(GenericResponse {
-                    status: "fail".to_string(),
-                    message: format!("something went wrong {}", e.to_string()),
-                }),
-            }
+    body: web::Json<ValidateOTPSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let attempts = AttemptKeys::new(OTP_SCOPE, Some(&u.id), &req);
+    attempts.check(&data).await?;
+
+    let cipher = cipher.into_inner();
+    let now = clock.now();
+    let body = body.into_inner();
+    let method = match (&body.recovery_code, &body.email_code) {
+        (Some(_), _) => RECOVERY_METHOD,
+        (None, Some(_)) => EMAIL_METHOD,
+        (None, None) => TOTP_METHOD,
+    };
+    let s = settings.clone();
+    let (us, is_valid) = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        if us.second_factor().is_none() {
+            return Err(AppError::Forbidden("2FA not enabled".to_string()));
         }
-        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
+
+        let is_valid = match (&body.token, &body.recovery_code, &body.email_code) {
+            (_, Some(recovery_code), _) => {
+                db.use_recovery_code(&us.id, &hash_recovery_code(&s.hash_secret, recovery_code))?
+            }
+            (_, None, Some(email_code)) => {
+                us.email_otp_enabled && check_email_otp(db, &s, &us.id, email_code)?
+            }
+            (Some(_), None, None) if !us.otp_enabled => false,
+            (Some(token), None, None) => {
+                let otp_base32 = load_otp_secret(db, &cipher, &us)?;
+                check_totp(db, &s.totp, &us, &otp_base32, token, now)?
+            }
+            (None, None, None) => {
+                return Err(AppError::Validation(
+                    "one of token, recovery_code or email_code is required".to_string(),
+                ))
+            }
+        };
+        Ok((us, is_valid))
+    })
+    .await?;
+
+    let client = ClientInfo::from_request(&req);
+    let outcome = if is_valid {
+        Outcome::Success
+    } else {
+        Outcome::Failure
+    };
+    let event =
+        AuditEvent::new(AuditAction::OtpValidate, outcome, Some(&us.id), &client).detail(method);
+    if !is_valid {
+        record(&data, event).await?;
+        attempts.record_failure(&data).await?;
+        return Err(invalid_code());
     }
+    attempts.record_success(&data).await?;
+
+    let expires_in = settings.tokens.access_token_ttl_secs;
+    let (token_str, refresh_token) = run(&data, move |db| {
+        db.add_audit_event(event)?;
+        issue_session_tokens(
+            db,
+            &settings,
+            &keys,
+            &us,
+            client,
+            &[PASSWORD_METHOD, method],
+        )
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(
+        json!({"status": "pass", "message": "verified", "jwt_token": token_str, "refresh_token": refresh_token, "expires_in": expires_in}),
+    ))
 }
 
 #[post("/auth/otp/disable")]
 async fn disable_otp_handler(
-    data: web::Data<Database>,
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let client = ClientInfo::from_request(&req);
+    let usr = run(&data, move |db| {
+        require_step_up(&u, &db.get_user_by_userid(&u.id)?, &settings.tokens)?;
+
+        let usr = db.update_totp_for_user(&u.id, None, false)?;
+        db.delete_recovery_codes(&usr.id)?;
+        db.add_audit_event(AuditEvent::new(
+            AuditAction::OtpDisable,
+            Outcome::Success,
+            Some(&usr.id),
+            &client,
+        ))?;
+        Ok(usr)
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .json(json!({"status":"pass","user": user_to_response(&usr), "otp_disabled": true})))
+}
+
+// sends a code by email, with the pre-auth token to (re)send the login code
+// or with a session token while enabling the email factor
+#[post(
+    "/auth/otp/email/send",
+    wrap = "HttpAuthentication::bearer(pre_auth_validator)"
+)]
+async fn send_email_otp_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let budget = AttemptKeys::new(MAIL_SCOPE, Some(&u.id), &req);
+    budget.check(&data).await?;
+
+    run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        if !us.email_verified {
+            return Err(email_not_verified());
+        }
+
+        send_email_otp(db, mailer.as_ref(), &settings, &us)
+    })
+    .await?;
+    budget.record_sent(&data).await?;
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "code sent".to_string(),
+    }))
+}
+
+// enables the email factor once a code sent by /auth/otp/email/send is
+// confirmed, it becomes the default if totp isn't set up
+#[post("/auth/otp/email/enable")]
+async fn enable_email_otp_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: web::Json<VerifyOTPSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    // the same lockout as /auth/otp/validate, a wrong guess leaves the code live
+    let attempts = AttemptKeys::new(OTP_SCOPE, Some(&u.id), &req);
+    attempts.check(&data).await?;
+
+    let token = body.into_inner().token;
+    let client = ClientInfo::from_request(&req);
+    let usr = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        let is_valid = check_email_otp(db, &settings, &us.id, &token)?;
+        let outcome = match is_valid {
+            true => Outcome::Success,
+            false => Outcome::Failure,
+        };
+        db.add_audit_event(AuditEvent::new(
+            AuditAction::EmailOtpEnable,
+            outcome,
+            Some(&us.id),
+            &client,
+        ))?;
+        if !is_valid {
+            return Ok(None);
+        }
+
+        let factor = match us.otp_enabled {
+            true => us.default_factor.as_str(),
+            false => SecondFactor::Email.as_str(),
+        };
+        db.set_email_otp_enabled(&us.id, true, factor).map(Some)
+    })
+    .await?;
+    let Some(usr) = usr else {
+        attempts.record_failure(&data).await?;
+        return Err(invalid_code());
+    };
+    attempts.record_success(&data).await?;
+    Ok(HttpResponse::Ok()
+        .json(json!({"status": "pass", "email_otp_enabled": true, "user": user_to_response(&usr)})))
+}
+
+#[post("/auth/otp/email/disable")]
+async fn disable_email_otp_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let client = ClientInfo::from_request(&req);
+    let usr = run(&data, move |db| {
+        require_step_up(&u, &db.get_user_by_userid(&u.id)?, &settings.tokens)?;
+
+        let usr = db.set_email_otp_enabled(&u.id, false, SecondFactor::Totp.as_str())?;
+        db.add_audit_event(AuditEvent::new(
+            AuditAction::EmailOtpDisable,
+            Outcome::Success,
+            Some(&usr.id),
+            &client,
+        ))?;
+        Ok(usr)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(
+        json!({"status": "pass", "email_otp_enabled": false, "user": user_to_response(&usr)}),
+    ))
+}
+
+#[post("/auth/otp/default-factor")]
+async fn default_factor_handler(
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: web::Json<DefaultFactorSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let factor = body.factor;
+    let usr = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        require_step_up(&u, &us, &settings.tokens)?;
+        let enabled = match factor {
+            SecondFactor::Totp => us.otp_enabled,
+            SecondFactor::Email => us.email_otp_enabled,
+        };
+        if !enabled {
+            return Err(AppError::Validation(format!(
+                "{} is not enabled",
+                factor.as_str()
+            )));
+        }
+
+        db.set_default_factor(&us.id, factor.as_str())
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "user": user_to_response(&usr)})))
+}
+
+#[get("/auth/otp/recovery-codes")]
+async fn recovery_codes_status_handler(
+    data: web::Data<dyn UserStore>,
     req_user: Option<ReqData<TokenClaims>>,
-) -> impl Responder {
-    match req_user {
-        Some(u) => {
-            let res = data.update_totp_for_user(&u.id, "", "", false);
-            match res {
-                Ok(usr) => HttpResponse::Ok().json(
-                    json!({"status":"pass","user": user_to_response(&usr), "otp_disabled": true}),
-                ),
-                Err(e) => {
-                    return HttpResponse::NotFound().json(GenericResponse {
-                        status: "fail".to_string(),
-                        message: format!("something went wrong {}", e.to_string()),
-                    });
-                }
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let remaining = run(&data, move |db| db.count_unused_recovery_codes(&u.id)).await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "remaining": remaining})))
+}
+
+#[post("/auth/otp/recovery-codes/regenerate")]
+async fn regenerate_recovery_codes_handler(
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let codes = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        if !us.otp_enabled {
+            return Err(AppError::Forbidden("2FA not enabled".to_string()));
+        }
+        require_step_up(&u, &us, &settings.tokens)?;
+
+        issue_recovery_codes(db, &settings, &us.id)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "recovery_codes": codes})))
+}
+
+// always answers the same way so it can't be used to find registered emails,
+// the mail budget included
+#[post("/auth/password/forgot")]
+async fn forgot_password_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
+    body: web::Json<ForgotPasswordSchema>,
+) -> Result<HttpResponse, AppError> {
+    let entered_email = body.into_inner().email;
+    let user = find_user_by_email(&data, &entered_email).await?;
+    let budget = AttemptKeys::new(MAIL_SCOPE, user.as_ref().map(|u| u.id.as_str()), &req)
+        .or_unknown_account(&entered_email);
+    budget.check(&data).await?;
+
+    if let Some(user) = user {
+        run(&data, move |db| {
+            send_password_reset(db, mailer.as_ref(), &settings, &user)
+        })
+        .await?;
+    }
+    budget.record_sent(&data).await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "if the email is registered, a reset link has been sent".to_string(),
+    }))
+}
+
+// sets a new password and ends every session, 2FA users have to pass their
+// second factor as well so the reset mail alone can't take over the account
+#[post("/auth/password/reset")]
+#[allow(clippy::too_many_arguments)]
+async fn reset_password_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    hasher: web::Data<HashPool>,
+    policy: web::Data<PasswordPolicy>,
+    cipher: web::Data<SecretCipher>,
+    clock: web::Data<dyn Clock>,
+    settings: web::Data<Settings>,
+    body: web::Json<ResetPasswordSchema>,
+) -> Result<HttpResponse, AppError> {
+    let invalid_token = || AppError::Unauthorized("reset token is invalid or expired".to_string());
+
+    let body = body.into_inner();
+    let (token, s) = (body.token.to_owned(), settings.clone());
+    let (reset, us) = run(&data, move |db| {
+        let reset = find_password_reset(db, &s, &token)?.ok_or_else(invalid_token)?;
+        let us = db.get_user_by_userid(&reset.user_id)?;
+        Ok((reset, us))
+    })
+    .await?;
+    policy
+        .check("password", &body.password, &[&us.email, &us.name])
+        .await?;
+
+    let client = ClientInfo::from_request(&req);
+    let password_event = |outcome, user_id: &str| {
+        AuditEvent::new(AuditAction::PasswordChange, outcome, Some(user_id), &client)
+    };
+    if us.otp_enabled {
+        let attempts = AttemptKeys::new(OTP_SCOPE, Some(&us.id), &req);
+        attempts.check(&data).await?;
+
+        let cipher = cipher.into_inner();
+        let now = clock.now();
+        let (us, otp_token, recovery_code) = (
+            us.clone(),
+            body.otp_token.clone(),
+            body.recovery_code.clone(),
+        );
+        let is_valid = run(&data, move |db| match (otp_token, recovery_code) {
+            (_, Some(recovery_code)) => db.use_recovery_code(
+                &us.id,
+                &hash_recovery_code(&settings.hash_secret, &recovery_code),
+            ),
+            (Some(token), None) => {
+                let otp_base32 = load_otp_secret(db, &cipher, &us)?;
+                check_totp(db, &settings.totp, &us, &otp_base32, &token, now)
             }
+            (None, None) => Err(AppError::Forbidden(
+                "2FA is enabled, otp_token or recovery_code is required".to_string(),
+            )),
+        })
+        .await?;
+
+        if !is_valid {
+            let event = password_event(Outcome::Failure, &reset.user_id)
+                .detail("reset, wrong second factor");
+            record(&data, event).await?;
+            attempts.record_failure(&data).await?;
+            return Err(invalid_code());
+        }
+        attempts.record_success(&data).await?;
+    }
+
+    let hash = hasher.hash(&body.password).await?;
+    let event = password_event(Outcome::Success, &us.id).detail("reset");
+    let reset_done = run(&data, move |db| {
+        let reset_done = db.reset_password(&reset.id, &us.id, &hash)?;
+        if reset_done {
+            db.add_audit_event(event)?;
         }
-        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
+        Ok(reset_done)
+    })
+    .await?;
+    if !reset_done {
+        return Err(invalid_token());
     }
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "password has been reset, log in again".to_string(),
+    }))
+}
+
+// exchanges a refresh token for a new access token, the refresh token is
+// rotated on every use and presenting an already rotated one revokes the
+// whole family since it means the token was leaked
+#[post("/auth/token/refresh")]
+async fn refresh_token_handler(
+    data: web::Data<dyn UserStore>,
+    keys: web::Data<JwtKeys>,
+    settings: web::Data<Settings>,
+    body: web::Json<RefreshTokenSchema>,
+) -> Result<HttpResponse, AppError> {
+    let invalid_token =
+        || AppError::Unauthorized("refresh token is invalid or expired".to_string());
+
+    let token_hash = hash_refresh_token(&body.refresh_token);
+    let s = settings.clone();
+    let (user, session, refresh_token) = run(&data, move |db| {
+        let current = match db.get_refresh_token_by_hash(&token_hash) {
+            Ok(token) => token,
+            Err(AppError::NotFound(_)) => return Err(invalid_token()),
+            Err(e) => return Err(e),
+        };
+
+        let now = Utc::now().naive_utc();
+        if current.expires_at <= now {
+            return Err(invalid_token());
+        }
+        let user = db.get_user_by_userid(&current.user_id)?;
+        if user.locked_at.is_some() {
+            return Err(account_locked());
+        }
+
+        let (refresh_token, record) =
+            new_refresh_token(&s.tokens, &current.user_id, &current.family_id);
+        let rotated = match current.revoked_at {
+            Some(_) => false,
+            None => db.rotate_refresh_token(&current.id, record)?,
+        };
+        if !rotated {
+            db.revoke_session(&current.family_id)?;
+            return Err(invalid_token());
+        }
+        db.touch_session(&current.family_id, now)?;
+        let session = db
+            .get_session(&current.family_id)?
+            .ok_or_else(invalid_token)?;
+        Ok((user, session, refresh_token))
+    })
+    .await?;
+
+    let token_str: String = sign_token(
+        &keys,
+        TokenClaims::session(&user, &session, &settings.tokens),
+    );
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "jwt_token": token_str, "refresh_token": refresh_token, "expires_in": settings.tokens.access_token_ttl_secs})))
+}
+
+// ends the session of the refresh token, which also stops its access tokens
+#[post("/auth/logout")]
+async fn logout_handler(
+    data: web::Data<dyn UserStore>,
+    body: web::Json<RefreshTokenSchema>,
+) -> Result<HttpResponse, AppError> {
+    let token_hash = hash_refresh_token(&body.refresh_token);
+    run(&data, move |db| {
+        match db.get_refresh_token_by_hash(&token_hash) {
+            Ok(token) => db.revoke_session(&token.family_id).map(|_| ()),
+            Err(AppError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "logged out".to_string(),
+    }))
+}
+
+// proves the user again within their session, with a totp (or recovery) code
+// when 2FA is set up and the password otherwise. returns an access token with a fresh
+// auth_time for the sensitive routes, see `require_step_up`
+#[post("/auth/step-up")]
+#[allow(clippy::too_many_arguments)]
+async fn step_up_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    hasher: web::Data<HashPool>,
+    cipher: web::Data<SecretCipher>,
+    keys: web::Data<JwtKeys>,
+    clock: web::Data<dyn Clock>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: web::Json<StepUpSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let session_id = current_session_id(&u)?;
+    let user_id = u.id.to_owned();
+    let us = run(&data, move |db| db.get_user_by_userid(&user_id)).await?;
+
+    let body = body.into_inner();
+    let (scope, method) = match (us.otp_enabled, &body.recovery_code) {
+        (true, Some(_)) => (OTP_SCOPE, RECOVERY_METHOD),
+        (true, None) => (OTP_SCOPE, TOTP_METHOD),
+        (false, _) => (LOGIN_SCOPE, PASSWORD_METHOD),
+    };
+    let attempts = AttemptKeys::new(scope, Some(&us.id), &req);
+    attempts.check(&data).await?;
+
+    let is_valid = match (method, body.token, body.recovery_code, body.password) {
+        (RECOVERY_METHOD, _, Some(recovery_code), _) => {
+            let (user_id, code_hash) = (
+                us.id.clone(),
+                hash_recovery_code(&settings.hash_secret, &recovery_code),
+            );
+            run(&data, move |db| db.use_recovery_code(&user_id, &code_hash)).await?
+        }
+        (TOTP_METHOD, Some(token), ..) => {
+            let (cipher, us, s) = (cipher.into_inner(), us.clone(), settings.clone());
+            let now = clock.now();
+            run(&data, move |db| {
+                let otp_base32 = load_otp_secret(db, &cipher, &us)?;
+                check_totp(db, &s.totp, &us, &otp_base32, &token, now)
+            })
+            .await?
+        }
+        (PASSWORD_METHOD, _, _, Some(password)) => hasher.verify(&us.password, &password).await?,
+        _ => {
+            return Err(AppError::Validation(match method {
+                TOTP_METHOD => "2FA is enabled, token or recovery_code is required".to_string(),
+                _ => "password is required".to_string(),
+            }))
+        }
+    };
+
+    let client = ClientInfo::from_request(&req);
+    let outcome = if is_valid {
+        Outcome::Success
+    } else {
+        Outcome::Failure
+    };
+    let event = AuditEvent::new(AuditAction::StepUp, outcome, Some(&us.id), &client).detail(method);
+    if !is_valid {
+        record(&data, event).await?;
+        attempts.record_failure(&data).await?;
+        return Err(match method {
+            PASSWORD_METHOD => incorrect_credentials(),
+            _ => invalid_code(),
+        });
+    }
+    attempts.record_success(&data).await?;
+
+    let s = settings.clone();
+    let token_str = run(&data, move |db| {
+        let session = db
+            .get_session(&session_id)?
+            .ok_or_else(|| AppError::NotFound("session".to_string()))?;
+        let session = db.step_up_session(
+            &session.id,
+            &with_method(&session, method),
+            Utc::now().naive_utc(),
+        )?;
+        db.add_audit_event(event)?;
+        Ok(sign_token(
+            &keys,
+            TokenClaims::session(&us, &session, &s.tokens),
+        ))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "jwt_token": token_str, "expires_in": settings.tokens.access_token_ttl_secs})))
+}
+
+// needs the current password, and a recent totp code when 2FA is on. every
+// other session is logged out, the one it was changed from stays
+#[post("/auth/password/change")]
+async fn change_password_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    hasher: web::Data<HashPool>,
+    policy: web::Data<PasswordPolicy>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: web::Json<ChangePasswordSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let session_id = current_session_id(&u)?;
+    let user_id = u.id.to_owned();
+    let us = run(&data, move |db| db.get_user_by_userid(&user_id)).await?;
+    if us.otp_enabled {
+        require_step_up(&u, &us, &settings.tokens)?;
+    }
+    policy
+        .check("new_password", &body.new_password, &[&us.email, &us.name])
+        .await?;
+
+    let attempts = AttemptKeys::new(LOGIN_SCOPE, Some(&us.id), &req);
+    attempts.check(&data).await?;
+
+    let client = ClientInfo::from_request(&req);
+    let password_event =
+        |outcome| AuditEvent::new(AuditAction::PasswordChange, outcome, Some(&us.id), &client);
+    if !hasher.verify(&us.password, &body.current_password).await? {
+        let event = password_event(Outcome::Failure).detail("change, wrong password");
+        record(&data, event).await?;
+        attempts.record_failure(&data).await?;
+        return Err(AppError::Unauthorized(
+            "current password is incorrect".to_string(),
+        ));
+    }
+    attempts.record_success(&data).await?;
+
+    let hash = hasher.hash(&body.new_password).await?;
+    let event = password_event(Outcome::Success).detail("change");
+    let changed = run(&data, move |db| {
+        let changed = db.change_password(&us.id, &us.password, &hash, &session_id)?;
+        if changed {
+            db.add_audit_event(event)?;
+        }
+        Ok(changed)
+    })
+    .await?;
+    if !changed {
+        return Err(AppError::Conflict(
+            "password was changed in the meantime, try again".to_string(),
+        ));
+    }
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "password changed, other sessions have been logged out".to_string(),
+    }))
+}
+
+// the new address has to be verified before the next login, the link goes to
+// it and the old address is told about the change
+#[post("/auth/email/change")]
+async fn change_email_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: web::Json<ChangeEmailSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let new_email = body.into_inner().email;
+    if new_email.parse::<lettre::Address>().is_err() {
+        return Err(AppError::Validation(
+            "email is not a valid address".to_string(),
+        ));
+    }
+
+    let client = ClientInfo::from_request(&req);
+    let usr = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        require_step_up(&u, &us, &settings.tokens)?;
+        if db.if_user_exists(&new_email)? {
+            return Err(AppError::Conflict(format!(
+                "user with email {new_email} already exists"
+            )));
+        }
+
+        let usr = db.change_email(&us.id, &new_email)?;
+        db.add_audit_event(
+            AuditEvent::new(
+                AuditAction::EmailChange,
+                Outcome::Success,
+                Some(&us.id),
+                &client,
+            )
+            .detail(format!("from {}", us.email)),
+        )?;
+        send_email_verification(mailer.as_ref(), &settings, &usr)?;
+        mailer.send(Mail {
+            to: us.email.to_owned(),
+            subject: "Your email address was changed".to_string(),
+            body: format!(
+                "The email address of your account was changed to {new_email}. If this wasn't you, reset your password right away."
+            ),
+        })?;
+        Ok(usr)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "user": user_to_response(&usr)})))
+}
+
+// deletes the user along with their factors, sessions and audit log, see
+// `UserStore::delete_user`
+#[post("/auth/account/delete")]
+async fn delete_account_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    settings: web::Data<Settings>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let client = ClientInfo::from_request(&req);
+    run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        require_step_up(&u, &us, &settings.tokens)?;
+        db.delete_user(&us.id)?;
+        db.add_audit_event(
+            AuditEvent::new(AuditAction::AccountDelete, Outcome::Success, None, &client)
+                .detail(format!("user {}", us.id)),
+        )
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "account deleted".to_string(),
+    }))
+}
+
+// everything stored about the current user as one JSON document
+#[get("/auth/account/export")]
+async fn export_account_handler(
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let (us, recovery_codes_remaining, sessions, events) = run(&data, move |db| {
+        let us = db.get_user_by_userid(&u.id)?;
+        let remaining = db.count_unused_recovery_codes(&us.id)?;
+        let sessions = db.export_sessions(&us.id)?;
+        let events = db.export_audit_events(&us.id)?;
+        Ok((us, remaining, sessions, events))
+    })
+    .await?;
+
+    let export = AccountExport {
+        exported_at: Utc::now().naive_utc(),
+        user: user_to_response(&us),
+        recovery_codes_remaining,
+        sessions: sessions
+            .iter()
+            .map(|s| session_to_response(s, None))
+            .collect(),
+        audit_log: events.iter().map(audit_event_to_response).collect(),
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"account.json\"",
+        ))
+        .json(export))
+}
+
+// the current user's active sessions, `current` marks the one the request
+// was made with
+#[get("/auth/sessions")]
+async fn list_sessions_handler(
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let current = u.sid.to_owned();
+    let sessions = run(&data, move |db| db.list_sessions(&u.id)).await?;
+    let sessions: Vec<SessionData> = sessions
+        .iter()
+        .map(|s| session_to_response(s, current.as_deref()))
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "sessions": sessions})))
+}
+
+#[post("/auth/sessions/{id}/revoke")]
+async fn revoke_session_handler(
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let session_id = path.into_inner();
+    run(&data, move |db| {
+        // someone else's session looks the same as a missing one
+        match db.get_session(&session_id)? {
+            Some(session) if session.user_id == u.id => db.revoke_session(&session.id),
+            _ => Err(AppError::NotFound("session".to_string())),
+        }
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "pass".to_string(),
+        message: "session revoked".to_string(),
+    }))
+}
+
+// includes the session the request was made with
+#[post("/auth/sessions/revoke-all")]
+async fn revoke_all_sessions_handler(
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let revoked = run(&data, move |db| db.revoke_all_sessions(&u.id)).await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "revoked": revoked})))
+}
+
+// the caller's own audit log, newest first
+#[get("/auth/audit-log")]
+async fn audit_log_handler(
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<PageSchema>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    audit_log_page(&data, u.id, &query).await
+}
+
+#[get("/auth/audit-log/export")]
+async fn export_audit_log_handler(
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    audit_log_export(&data, u.id).await
+}
+
+// the admin routes, mounted under /admin and only reachable with the admin
+// role, see `middleware::require_role::RequireRole`
+
+#[get("/users")]
+async fn admin_list_users_handler(
+    data: web::Data<dyn UserStore>,
+    query: web::Query<PageSchema>,
+) -> Result<HttpResponse, AppError> {
+    let (offset, limit) = query.bounds();
+    let users = run(&data, move |db| db.list_users(offset, limit)).await?;
+    let users: Vec<UserData> = users.iter().map(user_to_response).collect();
+    Ok(HttpResponse::Ok()
+        .json(json!({"status": "pass", "users": users, "offset": offset, "limit": limit})))
+}
+
+// for users who lost every factor, they can enroll again after logging in
+#[post("/users/{id}/2fa/disable")]
+async fn admin_disable_2fa_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let user_id = path.into_inner();
+    let event = AuditEvent::new(
+        AuditAction::OtpDisable,
+        Outcome::Success,
+        Some(&user_id),
+        &ClientInfo::from_request(&req),
+    )
+    .detail(format!("every factor, by admin {}", u.id));
+    let usr = run(&data, move |db| {
+        let usr = db.disable_second_factors(&user_id)?;
+        db.add_audit_event(event)?;
+        Ok(usr)
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .json(json!({"status": "pass", "user": user_to_response(&usr), "otp_disabled": true})))
+}
+
+// ends every session of the user and blocks logging in until unlocked
+#[post("/users/{id}/lock")]
+async fn admin_lock_user_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let user_id = path.into_inner();
+    if user_id == u.id {
+        return Err(AppError::Validation(
+            "you can't lock your own account".to_string(),
+        ));
+    }
+
+    let event = AuditEvent::new(
+        AuditAction::Lockout,
+        Outcome::Success,
+        Some(&user_id),
+        &ClientInfo::from_request(&req),
+    )
+    .detail(format!("by admin {}", u.id));
+    let usr = run(&data, move |db| {
+        let usr = db.set_locked(&user_id, true)?;
+        db.add_audit_event(event)?;
+        Ok(usr)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "user": user_to_response(&usr)})))
+}
+
+// also lifts lockouts from failed attempts on the account
+#[post("/users/{id}/unlock")]
+async fn admin_unlock_user_handler(
+    req: HttpRequest,
+    data: web::Data<dyn UserStore>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let u = identity(req_user)?;
+    let user_id = path.into_inner();
+    let event = AuditEvent::new(
+        AuditAction::Unlock,
+        Outcome::Success,
+        Some(&user_id),
+        &ClientInfo::from_request(&req),
+    )
+    .detail(format!("by admin {}", u.id));
+    let usr = run(&data, move |db| {
+        let usr = db.set_locked(&user_id, false)?;
+        for scope in [LOGIN_SCOPE, OTP_SCOPE] {
+            db.clear_login_attempt(&account_key(scope, &usr.id))?;
+        }
+        db.add_audit_event(event)?;
+        Ok(usr)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"status": "pass", "user": user_to_response(&usr)})))
+}
+
+#[get("/users/{id}/audit-log")]
+async fn admin_audit_log_handler(
+    data: web::Data<dyn UserStore>,
+    path: web::Path<String>,
+    query: web::Query<PageSchema>,
+) -> Result<HttpResponse, AppError> {
+    audit_log_page(&data, path.into_inner(), &query).await
+}
+
+#[get("/users/{id}/audit-log/export")]
+async fn admin_export_audit_log_handler(
+    data: web::Data<dyn UserStore>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    audit_log_export(&data, path.into_inner()).await
+}
+
+async fn audit_log_page(
+    data: &web::Data<dyn UserStore>,
+    user_id: String,
+    query: &PageSchema,
+) -> Result<HttpResponse, AppError> {
+    let (offset, limit) = query.bounds();
+    let events = run(data, move |db| {
+        let us = db.get_user_by_userid(&user_id)?;
+        db.list_audit_events(&us.id, offset, limit)
+    })
+    .await?;
+    let events: Vec<AuditEventData> = events.iter().map(audit_event_to_response).collect();
+    Ok(HttpResponse::Ok()
+        .json(json!({"status": "pass", "events": events, "offset": offset, "limit": limit})))
+}
+
+// the whole log of the user as json lines, oldest first
+async fn audit_log_export(
+    data: &web::Data<dyn UserStore>,
+    user_id: String,
+) -> Result<HttpResponse, AppError> {
+    let events = run(data, move |db| {
+        let us = db.get_user_by_userid(&user_id)?;
+        db.export_audit_events(&us.id)
+    })
+    .await?;
+
+    let mut body = String::new();
+    for event in &events {
+        let line = serde_json::to_string(&audit_event_to_response(event))
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        body.push_str(&line);
+        body.push('\n');
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit-log.jsonl\"",
+        ))
+        .body(body))
+}
+
+fn identity(req_user: Option<ReqData<TokenClaims>>) -> Result<TokenClaims, AppError> {
+    req_user
+        .map(ReqData::into_inner)
+        .ok_or_else(|| AppError::Unauthorized("Unable to verify identity".to_string()))
+}
+
+fn current_session_id(claims: &TokenClaims) -> Result<String, AppError> {
+    claims
+        .sid
+        .to_owned()
+        .ok_or_else(|| AppError::Unauthorized("Unable to verify identity".to_string()))
+}
+
+fn incorrect_credentials() -> AppError {
+    AppError::Unauthorized("incorrect username or password".to_string())
+}
+
+fn account_locked() -> AppError {
+    AppError::Forbidden("account is locked".to_string())
+}
+
+fn email_not_verified() -> AppError {
+    AppError::Forbidden("email address is not verified".to_string())
+}
+
+fn invalid_code() -> AppError {
+    AppError::Forbidden("Token is invalid or user doesn't exist".to_string())
+}
+
+// the plaintext is only around at login, so that is when hashes made with
+// older settings are replaced. best effort, the old hash keeps working
+async fn upgrade_password_hash(
+    data: &web::Data<dyn UserStore>,
+    hasher: &HashPool,
+    user: &User,
+    password: &str,
+) {
+    let upgraded = match hasher.hash(password).await {
+        Ok(new_hash) => {
+            let (user_id, old_hash) = (user.id.to_owned(), user.password.to_owned());
+            run(data, move |db| {
+                db.upgrade_password_hash(&user_id, &old_hash, &new_hash)
+            })
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = upgraded {
+        log::warn!("couldn't upgrade the password hash of {}: {e}", user.id);
+    }
+}
+
+// None for an address that isn't registered
+async fn find_user_by_email(
+    data: &web::Data<dyn UserStore>,
+    email: &str,
+) -> Result<Option<User>, AppError> {
+    let email = email.to_owned();
+    run(data, move |db| match db.get_user_by_email(&email) {
+        Ok(user) => Ok(Some(user)),
+        Err(AppError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    })
+    .await
+}
+
+// decrypts the user's otp secret, re-encrypting it with the current key when
+// it was written with an older one
+fn load_otp_secret(
+    data: &dyn UserStore,
+    cipher: &SecretCipher,
+    user: &User,
+) -> Result<String, AppError> {
+    let stored = match user.otp_base32.as_deref() {
+        Some(stored) if !stored.is_empty() => stored,
+        _ => return Err(AppError::Validation("2FA has not been set up".to_string())),
+    };
+
+    let (otp_base32, stale) = cipher.decrypt(stored)?;
+    if stale {
+        // best effort, the old key keeps working until this goes through
+        let _ = data.update_otp_secret(&user.id, &cipher.encrypt(&otp_base32));
+    }
+    Ok(otp_base32)
+}
+
+// a code is only accepted once, the matched time step is recorded and every
+// code at or before it is rejected from then on. checked with the params the
+// user enrolled with
+fn check_totp(
+    data: &dyn UserStore,
+    settings: &TotpSettings,
+    user: &User,
+    otp_base32: &str,
+    token: &str,
+    now: u64,
+) -> Result<bool, AppError> {
+    let totp = build_totp(settings, &TotpParams::of(user), otp_base32)?;
+    match matching_step(&totp, token, now) {
+        Some(step) => data.record_totp_step(&user.id, step as i64),
+        None => Ok(false),
+    }
+}
+
+// the sensitive routes need the user to have proven themselves recently, with
+// totp when it is set up and with their password otherwise. the claims are
+// enough since a fresh auth_time can only come from login or /auth/step-up
+fn require_step_up(
+    claims: &TokenClaims,
+    user: &User,
+    settings: &TokenSettings,
+) -> Result<(), AppError> {
+    // a recovery code stands in for the totp one, or losing the phone would
+    // leave no way to turn 2FA off and set it up again
+    let (method, accepted): (_, &[&str]) = match user.otp_enabled {
+        true => (TOTP_METHOD, &[TOTP_METHOD, RECOVERY_METHOD]),
+        false => (PASSWORD_METHOD, &[PASSWORD_METHOD]),
+    };
+    let max_age = settings.step_up_max_age_secs;
+    let fresh = Utc::now().timestamp() - claims.auth_time <= max_age;
+    match fresh && claims.amr.iter().any(|m| accepted.contains(&m.as_str())) {
+        true => Ok(()),
+        false => Err(AppError::StepUpRequired { method, max_age }),
+    }
+}
+
+// starts a new session for a fresh login
+fn issue_session_tokens(
+    data: &dyn UserStore,
+    settings: &Settings,
+    keys: &JwtKeys,
+    user: &User,
+    client: ClientInfo,
+    methods: &[&str],
+) -> Result<(String, String), AppError> {
+    let session = new_session(&user.id, client, methods, Utc::now().naive_utc());
+    let (refresh_token, record) = new_refresh_token(&settings.tokens, &user.id, &session.id);
+    let token_str: String =
+        sign_token(keys, TokenClaims::session(user, &session, &settings.tokens));
+    data.add_session(session)?;
+    data.add_refresh_token(record)?;
+    Ok((token_str, refresh_token))
+}
+
+// replaces the user's recovery codes, the plaintext codes are only ever
+// returned from here
+fn issue_recovery_codes(
+    data: &dyn UserStore,
+    settings: &Settings,
+    user_id: &str,
+) -> Result<Vec<String>, AppError> {
+    let codes = generate_recovery_codes();
+    let date_time = Utc::now().naive_utc();
+    let records = codes
+        .iter()
+        .map(|code| RecoveryCode {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_owned(),
+            code_hash: hash_recovery_code(&settings.hash_secret, code),
+            used_at: None,
+            created_at: date_time,
+        })
+        .collect();
+
+    data.replace_recovery_codes(user_id, records)?;
+    Ok(codes)
 }
@@ -0,0 +1,102 @@
+use std::net::IpAddr;
+
+use actix_web::{http::header, web, HttpRequest};
+use chrono::{Duration, NaiveDateTime};
+use uuid::Uuid;
+
+use crate::{model::Session, settings::Settings};
+
+// last_seen_at is only written once it is this far behind, so a busy client
+// doesn't cost a write on every request
+pub const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
+const MAX_USER_AGENT_LEN: usize = 512;
+
+// the authentication methods that end up in `Session::amr` and the jwt
+pub const PASSWORD_METHOD: &str = "pwd";
+pub const TOTP_METHOD: &str = "totp";
+pub const EMAIL_METHOD: &str = "email";
+pub const RECOVERY_METHOD: &str = "recovery";
+
+// what a session or audit entry shows about the device a request came from
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        ClientInfo {
+            ip: client_ip(req).map(|ip| ip.to_string()),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect()),
+        }
+    }
+}
+
+// the socket address, unless that is one of the trusted proxies. then it's
+// the nearest X-Forwarded-For entry that isn't, the ones further left are
+// up to the client
+fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = req
+        .app_data::<web::Data<Settings>>()
+        .map_or(&[][..], |settings| &settings.trusted_proxies);
+    if !trusted.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all(header::X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for entry in forwarded.iter().rev() {
+        match entry.trim().parse::<IpAddr>() {
+            Ok(ip) if trusted.contains(&ip) => continue,
+            Ok(ip) => return Some(ip),
+            Err(_) => break,
+        }
+    }
+    Some(peer)
+}
+
+pub fn new_session(
+    user_id: &str,
+    client: ClientInfo,
+    methods: &[&str],
+    now: NaiveDateTime,
+) -> Session {
+    Session {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_owned(),
+        user_agent: client.user_agent,
+        ip: client.ip,
+        created_at: now,
+        last_seen_at: now,
+        revoked_at: None,
+        amr: methods.join(","),
+        auth_time: now,
+    }
+}
+
+pub fn methods(session: &Session) -> Vec<String> {
+    session.amr.split(',').map(str::to_owned).collect()
+}
+
+// the session's methods with `method` added, for a step-up
+pub fn with_method(session: &Session, method: &str) -> String {
+    let mut methods = methods(session);
+    if !methods.iter().any(|m| m == method) {
+        methods.push(method.to_owned());
+    }
+    methods.join(",")
+}
+
+pub fn is_stale(session: &Session, now: NaiveDateTime) -> bool {
+    now - session.last_seen_at >= Duration::seconds(LAST_SEEN_RESOLUTION_SECS)
+}
@@ -0,0 +1,617 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::{
+    error::AppError,
+    model::{
+        AuditEvent, EmailOtp, LoginAttempt, PasswordReset, RecoveryCode, RefreshToken,
+        SecondFactor, Session, User,
+    },
+    otp::TotpParams,
+    store::UserStore,
+};
+
+#[derive(Default)]
+struct State {
+    users: HashMap<String, User>,
+    recovery_codes: Vec<RecoveryCode>,
+    refresh_tokens: Vec<RefreshToken>,
+    login_attempts: HashMap<String, LoginAttempt>,
+    email_otps: HashMap<String, EmailOtp>,
+    password_resets: Vec<PasswordReset>,
+    sessions: Vec<Session>,
+    // in the order they were added
+    audit_events: Vec<AuditEvent>,
+}
+
+impl State {
+    fn user_mut(&mut self, user_id: &str) -> Result<&mut User, AppError> {
+        self.users
+            .get_mut(user_id)
+            .ok_or_else(|| AppError::NotFound("record".to_string()))
+    }
+
+    // like the unique index on lower(email) in postgres
+    fn email_taken(&self, email: &str, except_user_id: Option<&str>) -> bool {
+        self.users
+            .values()
+            .any(|u| same_email(&u.email, email) && Some(u.id.as_str()) != except_user_id)
+    }
+
+    // every session of the user along with its refresh tokens
+    fn revoke_user_sessions(&mut self, user_id: &str, date_time: NaiveDateTime) -> usize {
+        for token in self.refresh_tokens.iter_mut() {
+            if token.user_id == user_id && token.revoked_at.is_none() {
+                token.revoked_at = Some(date_time);
+            }
+        }
+        let mut revoked = 0;
+        for session in self.sessions.iter_mut() {
+            if session.user_id == user_id && session.revoked_at.is_none() {
+                session.revoked_at = Some(date_time);
+                revoked += 1;
+            }
+        }
+        revoked
+    }
+}
+
+// emails compare like lower(email) = lower(..) in postgres
+fn same_email(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// keeps everything in memory behind one lock, which also makes every call
+// atomic like the transactions in `db::Database`. used by the tests and
+// with STORE=memory
+#[derive(Default)]
+pub struct InMemoryStore {
+    state: Mutex<State>,
+}
+
+impl InMemoryStore {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl UserStore for InMemoryStore {
+    fn if_user_exists(&self, entered_email: &str) -> Result<bool, AppError> {
+        Ok(self
+            .state()
+            .users
+            .values()
+            .any(|u| same_email(&u.email, entered_email)))
+    }
+
+    fn if_user_exists_userid(&self, user_id: &str) -> Result<bool, AppError> {
+        Ok(self.state().users.contains_key(user_id))
+    }
+
+    fn add_user(&self, user: User) -> Result<usize, AppError> {
+        let mut state = self.state();
+        if state.email_taken(&user.email, None) {
+            return Err(AppError::Conflict(format!(
+                "user with email {} already exists",
+                user.email
+            )));
+        }
+        state.users.insert(user.id.to_owned(), user);
+        Ok(1)
+    }
+
+    fn get_user_by_email(&self, user_email: &str) -> Result<User, AppError> {
+        self.state()
+            .users
+            .values()
+            .find(|u| same_email(&u.email, user_email))
+            .cloned()
+            .ok_or_else(|| AppError::NotFound("record".to_string()))
+    }
+
+    fn get_user_by_userid(&self, user_id: &str) -> Result<User, AppError> {
+        Ok(self.state().user_mut(user_id)?.clone())
+    }
+
+    fn upgrade_password_hash(
+        &self,
+        user_id: &str,
+        old_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool, AppError> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        if user.password != old_hash {
+            return Ok(false);
+        }
+        user.password = new_hash.to_owned();
+        Ok(true)
+    }
+
+    fn list_users(&self, offset: i64, limit: i64) -> Result<Vec<User>, AppError> {
+        let mut all: Vec<User> = self.state().users.values().cloned().collect();
+        all.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(all
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    fn set_role(&self, user_id: &str, role: &str) -> Result<User, AppError> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        user.role = role.to_owned();
+        Ok(user.clone())
+    }
+
+    fn change_password(
+        &self,
+        user_id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        keep_session_id: &str,
+    ) -> Result<bool, AppError> {
+        let mut state = self.state();
+        let date_time = Utc::now().naive_utc();
+        let user = state.user_mut(user_id)?;
+        if user.password != old_hash {
+            return Ok(false);
+        }
+        user.password = new_hash.to_owned();
+        user.updated_at = Some(date_time);
+
+        for token in state.refresh_tokens.iter_mut() {
+            if token.user_id == user_id
+                && token.family_id != keep_session_id
+                && token.revoked_at.is_none()
+            {
+                token.revoked_at = Some(date_time);
+            }
+        }
+        for session in state.sessions.iter_mut() {
+            if session.user_id == user_id
+                && session.id != keep_session_id
+                && session.revoked_at.is_none()
+            {
+                session.revoked_at = Some(date_time);
+            }
+        }
+        Ok(true)
+    }
+
+    fn change_email(&self, user_id: &str, email: &str) -> Result<User, AppError> {
+        let mut state = self.state();
+        if state.email_taken(email, Some(user_id)) {
+            return Err(AppError::Conflict(format!(
+                "user with email {email} already exists"
+            )));
+        }
+        let user = state.user_mut(user_id)?;
+        user.email = email.to_owned();
+        user.email_verified = false;
+        user.updated_at = Some(Utc::now().naive_utc());
+        Ok(user.clone())
+    }
+
+    fn delete_user(&self, user_id: &str) -> Result<usize, AppError> {
+        let mut state = self.state();
+        if state.users.remove(user_id).is_none() {
+            return Ok(0);
+        }
+        state.recovery_codes.retain(|c| c.user_id != user_id);
+        state.refresh_tokens.retain(|t| t.user_id != user_id);
+        state.email_otps.remove(user_id);
+        state.password_resets.retain(|r| r.user_id != user_id);
+        state.sessions.retain(|s| s.user_id != user_id);
+        state
+            .audit_events
+            .retain(|e| e.user_id.as_deref() != Some(user_id));
+        Ok(1)
+    }
+
+    fn set_locked(&self, user_id: &str, locked: bool) -> Result<User, AppError> {
+        let mut state = self.state();
+        let date_time = Utc::now().naive_utc();
+        let user = state.user_mut(user_id)?;
+        if !locked {
+            user.locked_at = None;
+            return Ok(user.clone());
+        }
+
+        user.locked_at = Some(date_time);
+        user.sessions_valid_after = Some(date_time);
+        let user = user.clone();
+        state.revoke_user_sessions(user_id, date_time);
+        Ok(user)
+    }
+
+    fn disable_second_factors(&self, user_id: &str) -> Result<User, AppError> {
+        let mut state = self.state();
+        state.recovery_codes.retain(|c| c.user_id != user_id);
+        state.email_otps.remove(user_id);
+        let user = state.user_mut(user_id)?;
+        user.otp_enabled = false;
+        user.otp_verified = false;
+        user.otp_base32 = None;
+        user.otp_auth_url = None;
+        user.otp_last_used_step = None;
+        user.otp_algorithm = None;
+        user.otp_digits = None;
+        user.otp_period = None;
+        user.email_otp_enabled = false;
+        user.default_factor = SecondFactor::Totp.as_str().to_string();
+        Ok(user.clone())
+    }
+
+    fn update_totp_for_user(
+        &self,
+        user_id: &str,
+        secret: Option<(&str, &TotpParams)>,
+        verified: bool,
+    ) -> Result<User, AppError> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        if !verified {
+            user.otp_base32 = secret.map(|(encrypted, _)| encrypted.to_owned());
+            user.otp_auth_url = None;
+            user.otp_last_used_step = None;
+            user.otp_algorithm = secret.map(|(_, params)| params.algorithm.clone());
+            user.otp_digits = secret.map(|(_, params)| params.digits as i32);
+            user.otp_period = secret.map(|(_, params)| params.period as i32);
+        }
+        user.otp_enabled = verified;
+        user.otp_verified = verified;
+        Ok(user.clone())
+    }
+
+    fn update_otp_secret(
+        &self,
+        user_id: &str,
+        encrypted_otp_base32: &str,
+    ) -> Result<usize, AppError> {
+        let mut state = self.state();
+        match state.users.get_mut(user_id) {
+            Some(user) => {
+                user.otp_base32 = Some(encrypted_otp_base32.to_owned());
+                user.otp_auth_url = None;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn record_totp_step(&self, user_id: &str, step: i64) -> Result<bool, AppError> {
+        let mut state = self.state();
+        match state.users.get_mut(user_id) {
+            Some(user) if user.otp_last_used_step.is_none_or(|last| last < step) => {
+                user.otp_last_used_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        codes: Vec<RecoveryCode>,
+    ) -> Result<usize, AppError> {
+        let mut state = self.state();
+        state.recovery_codes.retain(|c| c.user_id != user_id);
+        let count = codes.len();
+        state.recovery_codes.extend(codes);
+        Ok(count)
+    }
+
+    fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool, AppError> {
+        let mut state = self.state();
+        let code = state
+            .recovery_codes
+            .iter_mut()
+            .find(|c| c.user_id == user_id && c.code_hash == code_hash && c.used_at.is_none());
+        match code {
+            Some(code) => {
+                code.used_at = Some(Utc::now().naive_utc());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn count_unused_recovery_codes(&self, user_id: &str) -> Result<i64, AppError> {
+        Ok(self
+            .state()
+            .recovery_codes
+            .iter()
+            .filter(|c| c.user_id == user_id && c.used_at.is_none())
+            .count() as i64)
+    }
+
+    fn delete_recovery_codes(&self, user_id: &str) -> Result<usize, AppError> {
+        let mut state = self.state();
+        let before = state.recovery_codes.len();
+        state.recovery_codes.retain(|c| c.user_id != user_id);
+        Ok(before - state.recovery_codes.len())
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken) -> Result<usize, AppError> {
+        self.state().refresh_tokens.push(token);
+        Ok(1)
+    }
+
+    fn get_refresh_token_by_hash(&self, hash: &str) -> Result<RefreshToken, AppError> {
+        self.state()
+            .refresh_tokens
+            .iter()
+            .find(|t| t.token_hash == hash)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound("record".to_string()))
+    }
+
+    fn rotate_refresh_token(&self, old_id: &str, new: RefreshToken) -> Result<bool, AppError> {
+        let mut state = self.state();
+        let old = state
+            .refresh_tokens
+            .iter_mut()
+            .find(|t| t.id == old_id && t.revoked_at.is_none());
+        match old {
+            Some(old) => {
+                old.revoked_at = Some(Utc::now().naive_utc());
+                state.refresh_tokens.push(new);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn add_session(&self, session: Session) -> Result<usize, AppError> {
+        self.state().sessions.push(session);
+        Ok(1)
+    }
+
+    fn get_session(&self, session_id: &str) -> Result<Option<Session>, AppError> {
+        Ok(self
+            .state()
+            .sessions
+            .iter()
+            .find(|s| s.id == session_id)
+            .cloned())
+    }
+
+    fn list_sessions(&self, user_id: &str) -> Result<Vec<Session>, AppError> {
+        let mut active: Vec<Session> = self
+            .state()
+            .sessions
+            .iter()
+            .filter(|s| s.user_id == user_id && s.revoked_at.is_none())
+            .cloned()
+            .collect();
+        active.sort_by_key(|s| std::cmp::Reverse(s.last_seen_at));
+        Ok(active)
+    }
+
+    fn touch_session(
+        &self,
+        session_id: &str,
+        last_seen_at: NaiveDateTime,
+    ) -> Result<usize, AppError> {
+        let mut state = self.state();
+        match state.sessions.iter_mut().find(|s| s.id == session_id) {
+            Some(session) => {
+                session.last_seen_at = last_seen_at;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn revoke_session(&self, session_id: &str) -> Result<usize, AppError> {
+        let mut state = self.state();
+        let date_time = Utc::now().naive_utc();
+        for token in state.refresh_tokens.iter_mut() {
+            if token.family_id == session_id && token.revoked_at.is_none() {
+                token.revoked_at = Some(date_time);
+            }
+        }
+        match state
+            .sessions
+            .iter_mut()
+            .find(|s| s.id == session_id && s.revoked_at.is_none())
+        {
+            Some(session) => {
+                session.revoked_at = Some(date_time);
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn revoke_all_sessions(&self, user_id: &str) -> Result<usize, AppError> {
+        Ok(self
+            .state()
+            .revoke_user_sessions(user_id, Utc::now().naive_utc()))
+    }
+
+    fn export_sessions(&self, user_id: &str) -> Result<Vec<Session>, AppError> {
+        Ok(self
+            .state()
+            .sessions
+            .iter()
+            .filter(|s| s.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    fn step_up_session(
+        &self,
+        session_id: &str,
+        amr: &str,
+        auth_time: NaiveDateTime,
+    ) -> Result<Session, AppError> {
+        let mut state = self.state();
+        let session = state
+            .sessions
+            .iter_mut()
+            .find(|s| s.id == session_id)
+            .ok_or_else(|| AppError::NotFound("session".to_string()))?;
+        session.amr = amr.to_owned();
+        session.auth_time = auth_time;
+        Ok(session.clone())
+    }
+
+    fn add_audit_event(&self, event: AuditEvent) -> Result<usize, AppError> {
+        self.state().audit_events.push(event);
+        Ok(1)
+    }
+
+    fn list_audit_events(
+        &self,
+        user_id: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AppError> {
+        Ok(self
+            .state()
+            .audit_events
+            .iter()
+            .rev()
+            .filter(|e| e.user_id.as_deref() == Some(user_id))
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn export_audit_events(&self, user_id: &str) -> Result<Vec<AuditEvent>, AppError> {
+        Ok(self
+            .state()
+            .audit_events
+            .iter()
+            .filter(|e| e.user_id.as_deref() == Some(user_id))
+            .cloned()
+            .collect())
+    }
+
+    fn get_login_attempt(&self, key: &str) -> Result<Option<LoginAttempt>, AppError> {
+        Ok(self.state().login_attempts.get(key).cloned())
+    }
+
+    fn record_failed_attempt(
+        &self,
+        key: &str,
+        reset_before: NaiveDateTime,
+        lock_for: &dyn Fn(i32) -> Option<Duration>,
+    ) -> Result<LoginAttempt, AppError> {
+        let mut state = self.state();
+        let date_time = Utc::now().naive_utc();
+        let failures = match state.login_attempts.get(key) {
+            Some(attempt) if attempt.last_failure_at > reset_before => attempt.failures + 1,
+            _ => 1,
+        };
+        let attempt = LoginAttempt {
+            attempt_key: key.to_owned(),
+            failures,
+            locked_until: lock_for(failures).map(|d| date_time + d),
+            last_failure_at: date_time,
+        };
+        state.login_attempts.insert(key.to_owned(), attempt.clone());
+        Ok(attempt)
+    }
+
+    fn clear_login_attempt(&self, key: &str) -> Result<usize, AppError> {
+        Ok(self.state().login_attempts.remove(key).map_or(0, |_| 1))
+    }
+
+    fn set_email_otp(&self, otp: EmailOtp) -> Result<usize, AppError> {
+        self.state().email_otps.insert(otp.user_id.to_owned(), otp);
+        Ok(1)
+    }
+
+    fn consume_email_otp(&self, user_id: &str, code_hash: &str) -> Result<bool, AppError> {
+        let mut state = self.state();
+        let matches = state.email_otps.get(user_id).is_some_and(|otp| {
+            otp.code_hash == code_hash && otp.expires_at > Utc::now().naive_utc()
+        });
+        if matches {
+            state.email_otps.remove(user_id);
+        }
+        Ok(matches)
+    }
+
+    fn set_email_otp_enabled(
+        &self,
+        user_id: &str,
+        enabled: bool,
+        factor: &str,
+    ) -> Result<User, AppError> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        user.email_otp_enabled = enabled;
+        user.default_factor = factor.to_owned();
+        Ok(user.clone())
+    }
+
+    fn verify_email(&self, email: &str) -> Result<Option<User>, AppError> {
+        let mut state = self.state();
+        Ok(state
+            .users
+            .values_mut()
+            .find(|user| same_email(&user.email, email))
+            .map(|user| {
+                user.email_verified = true;
+                user.clone()
+            }))
+    }
+
+    fn set_default_factor(&self, user_id: &str, factor: &str) -> Result<User, AppError> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        user.default_factor = factor.to_owned();
+        Ok(user.clone())
+    }
+
+    fn add_password_reset(&self, reset: PasswordReset) -> Result<usize, AppError> {
+        self.state().password_resets.push(reset);
+        Ok(1)
+    }
+
+    fn get_password_reset_by_hash(&self, hash: &str) -> Result<Option<PasswordReset>, AppError> {
+        Ok(self
+            .state()
+            .password_resets
+            .iter()
+            .find(|r| r.token_hash == hash)
+            .cloned())
+    }
+
+    fn reset_password(
+        &self,
+        reset_id: &str,
+        user_id: &str,
+        password_hash: &str,
+    ) -> Result<bool, AppError> {
+        let mut state = self.state();
+        let date_time = Utc::now().naive_utc();
+        match state
+            .password_resets
+            .iter_mut()
+            .find(|r| r.id == reset_id && r.used_at.is_none())
+        {
+            Some(reset) => reset.used_at = Some(date_time),
+            None => return Ok(false),
+        }
+
+        if let Some(user) = state.users.get_mut(user_id) {
+            user.password = password_hash.to_owned();
+            user.sessions_valid_after = Some(date_time);
+            user.updated_at = Some(date_time);
+        }
+        state.revoke_user_sessions(user_id, date_time);
+        Ok(true)
+    }
+}
@@ -0,0 +1,220 @@
+pub mod memory;
+
+use std::sync::Arc;
+
+use actix_web::web;
+use chrono::{Duration, NaiveDateTime};
+
+use crate::{
+    db::Database,
+    error::AppError,
+    model::{
+        AuditEvent, EmailOtp, LoginAttempt, PasswordReset, RecoveryCode, RefreshToken, Session,
+        User,
+    },
+    otp::TotpParams,
+    settings::{Settings, StoreKind},
+    store::memory::InMemoryStore,
+};
+
+// everything the handlers need from storage, `db::Database` keeps it in
+// postgres and `memory::InMemoryStore` in memory for tests and local runs
+pub trait UserStore: Send + Sync {
+    fn if_user_exists(&self, entered_email: &str) -> Result<bool, AppError>;
+
+    fn if_user_exists_userid(&self, user_id: &str) -> Result<bool, AppError>;
+
+    fn add_user(&self, user: User) -> Result<usize, AppError>;
+
+    fn get_user_by_email(&self, user_email: &str) -> Result<User, AppError>;
+
+    fn get_user_by_userid(&self, user_id: &str) -> Result<User, AppError>;
+
+    // swaps in a rehash of the same password, returns false if the password
+    // was changed in the meantime
+    fn upgrade_password_hash(
+        &self,
+        user_id: &str,
+        old_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool, AppError>;
+
+    // sets a new password and ends every other session of the user, returns
+    // false if the password was changed in the meantime
+    fn change_password(
+        &self,
+        user_id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        keep_session_id: &str,
+    ) -> Result<bool, AppError>;
+
+    // the new address starts out unverified
+    fn change_email(&self, user_id: &str, email: &str) -> Result<User, AppError>;
+
+    // along with everything stored about the user
+    fn delete_user(&self, user_id: &str) -> Result<usize, AppError>;
+
+    // ordered by creation, oldest first
+    fn list_users(&self, offset: i64, limit: i64) -> Result<Vec<User>, AppError>;
+
+    fn set_role(&self, user_id: &str, role: &str) -> Result<User, AppError>;
+
+    // locking also ends every session of the user
+    fn set_locked(&self, user_id: &str, locked: bool) -> Result<User, AppError>;
+
+    // turns off totp and email codes and drops the recovery codes
+    fn disable_second_factors(&self, user_id: &str) -> Result<User, AppError>;
+
+    // verified = false stores a new (unverified) secret with its params and
+    // turns 2FA off, verified = true turns it on for the stored secret
+    fn update_totp_for_user(
+        &self,
+        user_id: &str,
+        secret: Option<(&str, &TotpParams)>,
+        verified: bool,
+    ) -> Result<User, AppError>;
+
+    fn update_otp_secret(
+        &self,
+        user_id: &str,
+        encrypted_otp_base32: &str,
+    ) -> Result<usize, AppError>;
+
+    // only moves the step forward, returns false if `step` was already used
+    fn record_totp_step(&self, user_id: &str, step: i64) -> Result<bool, AppError>;
+
+    fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        codes: Vec<RecoveryCode>,
+    ) -> Result<usize, AppError>;
+
+    fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool, AppError>;
+
+    fn count_unused_recovery_codes(&self, user_id: &str) -> Result<i64, AppError>;
+
+    fn delete_recovery_codes(&self, user_id: &str) -> Result<usize, AppError>;
+
+    fn add_refresh_token(&self, token: RefreshToken) -> Result<usize, AppError>;
+
+    fn get_refresh_token_by_hash(&self, hash: &str) -> Result<RefreshToken, AppError>;
+
+    // returns false if the old token was already revoked
+    fn rotate_refresh_token(&self, old_id: &str, new: RefreshToken) -> Result<bool, AppError>;
+
+    fn add_session(&self, session: Session) -> Result<usize, AppError>;
+
+    fn get_session(&self, session_id: &str) -> Result<Option<Session>, AppError>;
+
+    // the ones that aren't revoked, most recently used first
+    fn list_sessions(&self, user_id: &str) -> Result<Vec<Session>, AppError>;
+
+    fn touch_session(
+        &self,
+        session_id: &str,
+        last_seen_at: NaiveDateTime,
+    ) -> Result<usize, AppError>;
+
+    // also revokes every refresh token of the session
+    fn revoke_session(&self, session_id: &str) -> Result<usize, AppError>;
+
+    fn revoke_all_sessions(&self, user_id: &str) -> Result<usize, AppError>;
+
+    // every session of the user including ended ones, oldest first
+    fn export_sessions(&self, user_id: &str) -> Result<Vec<Session>, AppError>;
+
+    fn step_up_session(
+        &self,
+        session_id: &str,
+        amr: &str,
+        auth_time: NaiveDateTime,
+    ) -> Result<Session, AppError>;
+
+    // the audit log is append-only, nothing here changes or removes an entry
+    // except for deleting the user it belongs to
+    fn add_audit_event(&self, event: AuditEvent) -> Result<usize, AppError>;
+
+    // newest first
+    fn list_audit_events(
+        &self,
+        user_id: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AppError>;
+
+    // every entry of the user, oldest first
+    fn export_audit_events(&self, user_id: &str) -> Result<Vec<AuditEvent>, AppError>;
+
+    fn get_login_attempt(&self, key: &str) -> Result<Option<LoginAttempt>, AppError>;
+
+    fn record_failed_attempt(
+        &self,
+        key: &str,
+        reset_before: NaiveDateTime,
+        lock_for: &dyn Fn(i32) -> Option<Duration>,
+    ) -> Result<LoginAttempt, AppError>;
+
+    fn clear_login_attempt(&self, key: &str) -> Result<usize, AppError>;
+
+    fn set_email_otp(&self, otp: EmailOtp) -> Result<usize, AppError>;
+
+    fn consume_email_otp(&self, user_id: &str, code_hash: &str) -> Result<bool, AppError>;
+
+    fn set_email_otp_enabled(
+        &self,
+        user_id: &str,
+        enabled: bool,
+        factor: &str,
+    ) -> Result<User, AppError>;
+
+    fn set_default_factor(&self, user_id: &str, factor: &str) -> Result<User, AppError>;
+
+    // marks whoever has the address as verified, none if nobody has it (any more)
+    fn verify_email(&self, email: &str) -> Result<Option<User>, AppError>;
+
+    fn add_password_reset(&self, reset: PasswordReset) -> Result<usize, AppError>;
+
+    fn get_password_reset_by_hash(&self, hash: &str) -> Result<Option<PasswordReset>, AppError>;
+
+    // returns false if the reset was used in the meantime
+    fn reset_password(
+        &self,
+        reset_id: &str,
+        user_id: &str,
+        password_hash: &str,
+    ) -> Result<bool, AppError>;
+}
+
+// store calls may block (diesel and r2d2 do), handlers go through here so
+// they run on actix's blocking thread pool instead of stalling a worker
+pub async fn run<F, T>(store: &web::Data<dyn UserStore>, f: F) -> Result<T, AppError>
+where
+    F: FnOnce(&dyn UserStore) -> Result<T, AppError> + Send + 'static,
+    T: Send + 'static,
+{
+    let store = store.clone();
+    web::block(move || f(store.as_ref())).await?
+}
+
+// postgres applies pending migrations unless auto_migrate is off, which
+// leaves them to the `migrate` command, either way it refuses a schema that
+// doesn't match. memory loses everything on restart
+pub fn store_from_settings(settings: &Settings) -> Result<Arc<dyn UserStore>, String> {
+    match settings.store {
+        StoreKind::Postgres => {
+            let db = Database::new(&settings.database_url);
+            let checked = match settings.auto_migrate {
+                false => db.check_migrations(),
+                true => db.run_migrations().map(|applied| {
+                    for version in applied {
+                        log::info!("applied migration {version}");
+                    }
+                }),
+            };
+            checked.map_err(|e| e.to_string())?;
+            Ok(Arc::new(db))
+        }
+        StoreKind::Memory => Ok(Arc::new(InMemoryStore::default())),
+    }
+}
@@ -9,7 +9,10 @@ use actix_web::{
 use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenv::dotenv;
 use middlewares::auth_middleware::validator;
-use services::auth_service::{basic_auth, create_article, create_user};
+use services::auth_service::{
+    basic_auth, create_article, create_user, delete_article, get_article, get_articles,
+    patch_article, replace_article,
+};
 use sqlx::{mysql::MySqlPoolOptions, MySql, Pool};
 
 pub struct AppState {
@@ -37,7 +40,12 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("")
                     .wrap(bearer_middleware)
-                    .service(create_article),
+                    .service(create_article)
+                    .service(get_article)
+                    .service(get_articles)
+                    .service(replace_article)
+                    .service(patch_article)
+                    .service(delete_article),
             )
     })
     .bind(("127.0.0.1", 8080))?
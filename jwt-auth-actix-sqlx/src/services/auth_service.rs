@@ -1,6 +1,6 @@
 use actix_web::{
-    get, post,
-    web::{Data, Json, ReqData},
+    delete, get, patch, post, put,
+    web::{Data, Json, Path, Query, ReqData},
     HttpResponse, Responder,
 };
 use actix_web_httpauth::extractors::basic::BasicAuth;
@@ -11,6 +11,7 @@ use hmac::{Hmac, Mac};
 use jwt::SignWithKey;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use sqlx::{MySql, Pool};
 
 use crate::{middlewares::auth_middleware::TokenClaims, AppState};
 
@@ -39,13 +40,49 @@ struct CreateArticleBody {
     content: String,
 }
 
+// every field is optional, the ones left out keep their current value
+#[derive(Deserialize)]
+struct PatchArticleBody {
+    title: Option<String>,
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ArticlesQuery {
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
 #[derive(Serialize, sqlx::FromRow)]
 struct Article {
     id: u64,
     title: String,
     content: String,
-    lished_by: i32,
-    lished_on: Option<NaiveDateTime>,
+    published_by: u64,
+    published_on: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+struct ArticlesPage {
+    articles: Vec<Article>,
+    page: u32,
+    per_page: u32,
+    total: i64,
+}
+
+const DEFAULT_PER_PAGE: u32 = 10;
+const MAX_PER_PAGE: u32 = 100;
+
+// the page, page size and row offset asked for, pages start at 1 and
+// per_page is kept between 1 and MAX_PER_PAGE
+fn page_bounds(query: &ArticlesQuery) -> (u32, u32, u64) {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let offset = u64::from(page - 1) * u64::from(per_page);
+    (page, per_page, offset)
 }
 
 #[post("/user")]
@@ -149,3 +186,327 @@ async fn create_article(
         _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
     }
 }
+
+async fn fetch_article(db: &Pool<MySql>, id: u64) -> Result<Article, HttpResponse> {
+    match sqlx::query_as::<_, Article>(
+        "SELECT id, title, content, published_by, published_on FROM articles WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_one(db)
+    .await
+    {
+        Ok(article) => Ok(article),
+        Err(sqlx::Error::RowNotFound) => Err(HttpResponse::NotFound().json("Article not found")),
+        Err(error) => Err(HttpResponse::InternalServerError().json(format!("{:?}", error))),
+    }
+}
+
+// the article, if it exists and was published by the user making the request
+async fn fetch_owned_article(
+    db: &Pool<MySql>,
+    user: &TokenClaims,
+    id: u64,
+) -> Result<Article, HttpResponse> {
+    let article = fetch_article(db, id).await?;
+    check_publisher(&article, user)?;
+    Ok(article)
+}
+
+fn check_publisher(article: &Article, user: &TokenClaims) -> Result<(), HttpResponse> {
+    match article.published_by == user.id {
+        true => Ok(()),
+        false => Err(HttpResponse::Forbidden().json("Only the publisher can change this article")),
+    }
+}
+
+// callers check the ownership first, it is checked again here so a concurrent
+// change can't slip through
+async fn update_article(
+    state: &AppState,
+    user: &TokenClaims,
+    id: u64,
+    title: String,
+    content: String,
+) -> HttpResponse {
+    match sqlx::query(
+        "UPDATE articles SET title = ?, content = ?
+        WHERE id = ? AND published_by = ?;
+        ",
+    )
+    .bind(title)
+    .bind(content)
+    .bind(id)
+    .bind(user.id)
+    .execute(&state.db)
+    .await
+    {
+        Ok(_) => match fetch_article(&state.db, id).await {
+            Ok(article) => HttpResponse::Ok().json(article),
+            Err(response) => response,
+        },
+        Err(error) => HttpResponse::InternalServerError().json(format!("{:?}", error)),
+    }
+}
+
+#[get("/article/{id}")]
+async fn get_article(state: Data<AppState>, path: Path<u64>) -> impl Responder {
+    match fetch_article(&state.db, path.into_inner()).await {
+        Ok(article) => HttpResponse::Ok().json(article),
+        Err(response) => response,
+    }
+}
+
+#[get("/articles")]
+async fn get_articles(state: Data<AppState>, query: Query<ArticlesQuery>) -> impl Responder {
+    let (page, per_page, offset) = page_bounds(&query);
+
+    let total = match sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM articles")
+        .fetch_one(&state.db)
+        .await
+    {
+        Ok(total) => total,
+        Err(error) => return HttpResponse::InternalServerError().json(format!("{:?}", error)),
+    };
+
+    match sqlx::query_as::<_, Article>(
+        "SELECT id, title, content, published_by, published_on FROM articles
+        ORDER BY published_on DESC, id DESC
+        LIMIT ? OFFSET ?
+        ",
+    )
+    .bind(per_page)
+    .bind(offset)
+    .fetch_all(&state.db)
+    .await
+    {
+        Ok(articles) => HttpResponse::Ok().json(ArticlesPage {
+            articles,
+            page,
+            per_page,
+            total,
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(format!("{:?}", error)),
+    }
+}
+
+#[put("/article/{id}")]
+async fn replace_article(
+    state: Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: Path<u64>,
+    body: Json<CreateArticleBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let id = path.into_inner();
+            if let Err(response) = fetch_owned_article(&state.db, &user, id).await {
+                return response;
+            }
+
+            let article: CreateArticleBody = body.into_inner();
+            update_article(&state, &user, id, article.title, article.content).await
+        }
+        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
+    }
+}
+
+#[patch("/article/{id}")]
+async fn patch_article(
+    state: Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: Path<u64>,
+    body: Json<PatchArticleBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let id = path.into_inner();
+            let current = match fetch_owned_article(&state.db, &user, id).await {
+                Ok(article) => article,
+                Err(response) => return response,
+            };
+
+            let changes: PatchArticleBody = body.into_inner();
+            update_article(
+                &state,
+                &user,
+                id,
+                changes.title.unwrap_or(current.title),
+                changes.content.unwrap_or(current.content),
+            )
+            .await
+        }
+        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
+    }
+}
+
+#[delete("/article/{id}")]
+async fn delete_article(
+    state: Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: Path<u64>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let id = path.into_inner();
+            if let Err(response) = fetch_owned_article(&state.db, &user, id).await {
+                return response;
+            }
+
+            match sqlx::query("DELETE FROM articles WHERE id = ? AND published_by = ?;")
+                .bind(id)
+                .bind(user.id)
+                .execute(&state.db)
+                .await
+            {
+                Ok(_) => HttpResponse::Ok().json(format!("{:?}", "done")),
+                Err(error) => HttpResponse::InternalServerError().json(format!("{:?}", error)),
+            }
+        }
+        _ => HttpResponse::Unauthorized().json("Unable to verify identity"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App, HttpMessage};
+    use serde_json::{json, Value};
+    use sqlx::mysql::MySqlPoolOptions;
+
+    use super::*;
+
+    fn query(page: Option<u32>, per_page: Option<u32>) -> ArticlesQuery {
+        ArticlesQuery { page, per_page }
+    }
+
+    fn article(published_by: u64) -> Article {
+        Article {
+            id: 1,
+            title: "title".to_string(),
+            content: "content".to_string(),
+            published_by,
+            published_on: None,
+        }
+    }
+
+    #[test]
+    fn pages_start_at_one_and_their_size_is_clamped() {
+        assert_eq!(page_bounds(&query(None, None)), (1, DEFAULT_PER_PAGE, 0));
+        assert_eq!(page_bounds(&query(Some(0), Some(0))), (1, 1, 0));
+        assert_eq!(page_bounds(&query(Some(3), Some(20))), (3, 20, 40));
+        assert_eq!(
+            page_bounds(&query(Some(3), Some(1000))),
+            (3, MAX_PER_PAGE, 2 * u64::from(MAX_PER_PAGE))
+        );
+        // far out pages don't overflow the offset
+        assert_eq!(
+            page_bounds(&query(Some(u32::MAX), Some(MAX_PER_PAGE))).2,
+            u64::from(u32::MAX - 1) * u64::from(MAX_PER_PAGE)
+        );
+    }
+
+    #[test]
+    fn only_the_publisher_passes_the_ownership_check() {
+        assert!(check_publisher(&article(1), &TokenClaims { id: 1 }).is_ok());
+        let response = check_publisher(&article(1), &TokenClaims { id: 2 }).unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    // through the handlers, against the database from DATABASE_URL with
+    // init.sql applied: cargo test -- --ignored
+    #[actix_web::test]
+    #[ignore = "needs a mysql database, see DATABASE_URL"]
+    async fn only_the_publisher_can_change_or_delete_an_article() {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let db = MySqlPoolOptions::new()
+            .connect(&database_url)
+            .await
+            .unwrap();
+
+        let mut users = Vec::new();
+        for username in ["publisher", "someone-else"] {
+            let inserted = sqlx::query("INSERT INTO users (username, password) VALUES (?, '')")
+                .bind(username)
+                .execute(&db)
+                .await
+                .unwrap();
+            users.push(inserted.last_insert_id());
+        }
+        let (publisher, other) = (users[0], users[1]);
+        let id = sqlx::query(
+            "INSERT INTO articles (title, content, published_by) VALUES ('title', 'content', ?)",
+        )
+        .bind(publisher)
+        .execute(&db)
+        .await
+        .unwrap()
+        .last_insert_id();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(AppState { db: db.clone() }))
+                .service(get_article)
+                .service(get_articles)
+                .service(replace_article)
+                .service(patch_article)
+                .service(delete_article),
+        )
+        .await;
+        // what the bearer middleware leaves for the handlers
+        let call = |req: test::TestRequest, user: u64| {
+            let req = req.to_request();
+            req.extensions_mut().insert(TokenClaims { id: user });
+            test::call_service(&app, req)
+        };
+        let uri = format!("/article/{id}");
+
+        for req in [
+            test::TestRequest::put()
+                .uri(&uri)
+                .set_json(json!({"title": "taken", "content": "over"})),
+            test::TestRequest::patch()
+                .uri(&uri)
+                .set_json(json!({"title": "taken"})),
+            test::TestRequest::delete().uri(&uri),
+        ] {
+            let resp = call(req, other).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+
+        let resp = call(
+            test::TestRequest::patch()
+                .uri(&uri)
+                .set_json(json!({"title": "renamed"})),
+            publisher,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["title"], "renamed");
+        assert_eq!(body["content"], "content");
+
+        // the page size is clamped through the query string as well
+        let resp = call(
+            test::TestRequest::get().uri("/articles?page=0&per_page=1000"),
+            other,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["page"], 1);
+        assert_eq!(body["per_page"], MAX_PER_PAGE);
+
+        let resp = call(test::TestRequest::delete().uri(&uri), publisher).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call(test::TestRequest::get().uri(&uri), publisher).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for user in users {
+            sqlx::query("DELETE FROM users WHERE id = ?")
+                .bind(user)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+    }
+}